@@ -2,16 +2,15 @@ use std::os::raw::c_void;
 
 use windows::{
     Win32::{
-        Devices::Properties::{
-            DEVPROP_STORE_SYSTEM, DEVPROP_TYPE_STRING, DEVPROP_TYPE_UINT32, DEVPROPCOMPKEY,
-            DEVPROPERTY,
-        },
+        Devices::Properties::{DEVPROP_STORE_SYSTEM, DEVPROPCOMPKEY, DEVPROPERTY, DEVPROPTYPE},
         Foundation::DEVPROPKEY,
     },
     core::GUID,
 };
 use windows_strings::PCWSTR;
 
+use nut_hid_properties::{Property, PropertyValue};
+
 pub const fn get_comp_key(fmtid: GUID, pid: u32) -> DEVPROPCOMPKEY {
     DEVPROPCOMPKEY {
         Store: DEVPROP_STORE_SYSTEM,
@@ -25,7 +24,6 @@ pub const fn get_comp_key(fmtid: GUID, pid: u32) -> DEVPROPCOMPKEY {
 
 #[derive(Debug)]
 pub struct PropertiesStore {
-    strings: Vec<Box<widestring::U16CStr>>,
     data: Vec<Box<Vec<u8>>>,
     properties: Vec<DEVPROPERTY>,
 }
@@ -33,40 +31,19 @@ pub struct PropertiesStore {
 impl PropertiesStore {
     pub fn new() -> PropertiesStore {
         PropertiesStore {
-            strings: Vec::new(),
             data: Vec::new(),
             properties: Vec::new(),
         }
     }
 
-    pub fn add_string(&mut self, fmtid: GUID, pid: u32, value: &str) {
-        let key = get_comp_key(fmtid, pid);
-        let value = widestring::U16CString::from_str(value)
-            .unwrap()
-            .into_boxed_ucstr();
-
-        let value_ptr = value.as_ptr();
-        let value_len = (value.len() + 1) * size_of::<u16>();
-        self.strings.push(value);
-
-        let property = DEVPROPERTY {
-            Type: DEVPROP_TYPE_STRING,
-            CompKey: key,
-            BufferSize: value_len as u32,
-            Buffer: value_ptr as *mut c_void,
-        };
-        self.properties.push(property);
-    }
-
-    pub fn add_u32(&mut self, fmtid: GUID, pid: u32, value: u32) {
-        let key = get_comp_key(fmtid, pid);
+    pub fn add(&mut self, fmtid: GUID, property: &Property, value: &PropertyValue) {
+        let key = get_comp_key(fmtid, property.key);
 
-        let value: Vec<u8> = value.to_ne_bytes().into();
-        self.data.push(value.into());
+        self.data.push(value.encode().into());
         let value = self.data.last().unwrap();
 
         let property = DEVPROPERTY {
-            Type: DEVPROP_TYPE_UINT32,
+            Type: DEVPROPTYPE(property.kind.devprop_type()),
             CompKey: key,
             BufferSize: value.len() as u32,
             Buffer: value.as_ptr() as *mut c_void,
@@ -0,0 +1,273 @@
+//! Checks a device against what the Windows HID battery driver needs.
+//!
+//! Windows silently ignores a UPS whose descriptor lacks one of the usages it reads,
+//! so every problem is reported instead of stopping at the first one.
+
+use std::error::Error;
+use std::fmt;
+
+use super::*;
+use descriptor::{
+    AC_PRESENT, CAPACITY_MODE, CHARGING, DESIGN_CAPACITY, DISCHARGING, FULL_CHARGE_CAPACITY, Field,
+    PRESENT_STATUS, REMAINING_CAPACITY, RUN_TIME_TO_EMPTY,
+};
+
+/// A usage Windows reads from the device
+struct Required {
+    name: &'static str,
+    usage: u32,
+    /// Collection the usage has to be part of
+    collection: Option<u32>,
+}
+
+const REQUIRED: &[Required] = &[
+    Required {
+        name: "PresentStatus.ACPresent",
+        usage: AC_PRESENT,
+        collection: Some(PRESENT_STATUS),
+    },
+    Required {
+        name: "PresentStatus.Charging",
+        usage: CHARGING,
+        collection: Some(PRESENT_STATUS),
+    },
+    Required {
+        name: "PresentStatus.Discharging",
+        usage: DISCHARGING,
+        collection: Some(PRESENT_STATUS),
+    },
+    Required {
+        name: "RemainingCapacity",
+        usage: REMAINING_CAPACITY,
+        collection: None,
+    },
+    Required {
+        name: "FullChargeCapacity",
+        usage: FULL_CHARGE_CAPACITY,
+        collection: None,
+    },
+    Required {
+        name: "CapacityMode",
+        usage: CAPACITY_MODE,
+        collection: None,
+    },
+    Required {
+        name: "DesignCapacity",
+        usage: DESIGN_CAPACITY,
+        collection: None,
+    },
+    Required {
+        name: "RunTimeToEmpty",
+        usage: RUN_TIME_TO_EMPTY,
+        collection: None,
+    },
+];
+
+/// A single reason Windows would not use the device as a battery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckError {
+    MissingUsage(&'static str),
+    /// Windows reads the usage through a feature report
+    NotFeature(&'static str),
+    MissingString(u32),
+    MissingReport {
+        report_type: ReportType,
+        report_id: u8,
+    },
+    ReportSize {
+        report_type: ReportType,
+        report_id: u8,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::MissingUsage(name) => write!(f, "{name} is not in the descriptor"),
+            CheckError::NotFeature(name) => write!(f, "{name} is not a feature report"),
+            CheckError::MissingString(index) => write!(f, "string {index} is not defined"),
+            CheckError::MissingReport {
+                report_type,
+                report_id,
+            } => write!(f, "{report_type:?} report {report_id} has no value"),
+            CheckError::ReportSize {
+                report_type,
+                report_id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{report_type:?} report {report_id} has {actual} bytes, the descriptor declares {expected}"
+            ),
+        }
+    }
+}
+
+impl Error for CheckError {}
+
+fn check_usages(fields: &[Field], errors: &mut Vec<CheckError>) {
+    for required in REQUIRED {
+        let matching = fields
+            .iter()
+            .filter(|field| {
+                field.usage == Some(required.usage)
+                    && (required.collection.is_none() || field.collection == required.collection)
+            })
+            .collect::<Vec<_>>();
+
+        if matching.is_empty() {
+            errors.push(CheckError::MissingUsage(required.name));
+        } else if !matching
+            .iter()
+            .any(|field| field.report_type == ReportType::Feature)
+        {
+            errors.push(CheckError::NotFeature(required.name));
+        }
+    }
+}
+
+fn check_strings(data: &DeviceData, fields: &[Field], errors: &mut Vec<CheckError>) {
+    let mut indexes = fields
+        .iter()
+        .filter_map(|field| field.string_index)
+        .collect::<Vec<_>>();
+    indexes.sort();
+    indexes.dedup();
+
+    for index in indexes {
+        let resolved = u8::try_from(index)
+            .ok()
+            .and_then(|index| data.indexed_string(index));
+        if resolved.is_none() {
+            errors.push(CheckError::MissingString(index));
+        }
+    }
+}
+
+/* output reports are written by the host, they need no value */
+fn check_reports(data: &DeviceData, errors: &mut Vec<CheckError>) {
+    for ((report_type, report_id), expected) in descriptor::report_sizes(&data.report_descriptor) {
+        if report_type == ReportType::Output {
+            continue;
+        }
+        match data.reports.get(report_type, report_id) {
+            None => errors.push(CheckError::MissingReport {
+                report_type,
+                report_id,
+            }),
+            Some(report) if report.len() != expected => errors.push(CheckError::ReportSize {
+                report_type,
+                report_id,
+                expected,
+                actual: report.len(),
+            }),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Every problem keeping Windows from using the device as a battery
+pub fn check(data: &DeviceData) -> Vec<CheckError> {
+    let fields = descriptor::fields(&data.report_descriptor);
+    let mut errors = Vec::new();
+    check_usages(&fields, &mut errors);
+    check_strings(data, &fields, &mut errors);
+    check_reports(data, &mut errors);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_conform() {
+        let devices: Vec<(&str, Box<dyn Device>)> = vec![
+            (
+                "dummy",
+                Box::new(dummy::new_dummy_device(Default::default()).unwrap()),
+            ),
+            (
+                "sim",
+                Box::new(sim::new_sim_device(Default::default()).unwrap()),
+            ),
+            (
+                "nut",
+                Box::new(
+                    nut::new_nut_device(DeviceConfig::parse_url("nut://localhost").unwrap())
+                        .unwrap(),
+                ),
+            ),
+        ];
+        for (name, device) in devices {
+            assert_eq!(check(&device.data().read().unwrap()), [], "{name}");
+        }
+
+        /* replay and nutdump share the device data of nut */
+        assert_eq!(check(&nut::new_nut_device_data()), []);
+    }
+
+    #[test]
+    fn mini_is_not_a_battery() {
+        let device = mini::new_mini_device(Default::default());
+        let errors = check(&device.data().read().unwrap());
+        assert!(errors.contains(&CheckError::MissingUsage("RemainingCapacity")));
+        assert!(errors.contains(&CheckError::MissingUsage("PresentStatus.ACPresent")));
+    }
+
+    #[test]
+    fn report_problems() {
+        let mut data = nut::new_nut_device_data();
+        data.strings.clear();
+        data.report_descriptor.splice(
+            0..0,
+            [
+                0x05, 0x84, 0x75, 0x08, 0x95, 0x01, 0x85, 0x30, 0x79, 0x09, 0x09, 0x01, 0xB1, 0x02,
+            ],
+        );
+        data.reports.set(ReportType::Feature, 0x0E, [1, 2]);
+
+        let errors = check(&data);
+        assert_eq!(
+            errors,
+            [
+                CheckError::MissingString(9),
+                CheckError::ReportSize {
+                    report_type: ReportType::Feature,
+                    report_id: 0x0E,
+                    expected: 1,
+                    actual: 2
+                },
+                CheckError::MissingReport {
+                    report_type: ReportType::Feature,
+                    report_id: 0x30
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_report_type() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x05, 0x85, // USAGE_PAGE (Battery System)
+            0x85, 0x01, // REPORT_ID (1)
+            0x75, 0x08, // REPORT_SIZE (8)
+            0x95, 0x01, // REPORT_COUNT (1)
+            0x09, 0x66, // USAGE (RemainingCapacity)
+            0x81, 0x02, // INPUT
+        ];
+        let data = DeviceData {
+            reports: Reports::from_descriptor(&descriptor),
+            report_descriptor: descriptor.into(),
+            ..Default::default()
+        };
+
+        let errors = check(&data);
+        assert!(errors.contains(&CheckError::NotFeature("RemainingCapacity")));
+        assert!(errors.contains(&CheckError::MissingUsage("DesignCapacity")));
+        assert_eq!(errors.len(), REQUIRED.len());
+    }
+}
@@ -0,0 +1,25 @@
+//! Source of time for backends, replaced by a fake clock in tests.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wait until the clock moved on by `duration`
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real monotonic clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
@@ -0,0 +1,397 @@
+//! TOML configuration files describing one or more devices.
+//!
+//! ```toml
+//! [profiles.rack]
+//! hosts = ["nut1", "nut2:3494"]
+//! username = "monitor"
+//! password_env = "NUT_PASSWORD"
+//! poll = 5.0
+//!
+//! [[device]]
+//! name = "rack1"
+//! profile = "rack"
+//! ups = "ups1"
+//! identity = { product = "Rack 1 UPS" }
+//!
+//! [[device]]
+//! name = "lab"
+//! url = "nut://lab-nut/ups"
+//! options = { record = 'C:\ups.rec' }
+//! ```
+//!
+//! A device inherits every setting it does not set itself from its profile. The
+//! password is never stored in the file, only a reference to an environment variable
+//! or a file holding it. The reference is kept as the `password_env` or
+//! `password_file` option, so the driver reads the password when it connects and it
+//! never ends up in the device properties.
+
+use std::fs;
+
+use serde::Deserialize;
+
+use super::*;
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct Identity {
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct DeviceSection {
+    name: Option<String>,
+    profile: Option<String>,
+    backend: Option<String>,
+    /// Base configuration, the other settings override it
+    url: Option<String>,
+    hosts: Option<Vec<String>>,
+    port: Option<u32>,
+    ups: Option<String>,
+    username: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<String>,
+    poll: Option<f64>,
+    identity: Option<Identity>,
+    #[serde(default)]
+    options: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: BTreeMap<String, DeviceSection>,
+    #[serde(default, rename = "device")]
+    devices: Vec<DeviceSection>,
+}
+
+/// A device from a configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedConfig {
+    /// Name from the file, `device<n>` if not set
+    pub name: String,
+    pub config: DeviceConfig,
+}
+
+impl DeviceSection {
+    /// Fill in everything not set from the profile
+    fn inherit(self, profile: &DeviceSection) -> DeviceSection {
+        let profile = profile.clone();
+        let identity = match (self.identity, profile.identity) {
+            (Some(identity), Some(base)) => Some(Identity {
+                manufacturer: identity.manufacturer.or(base.manufacturer),
+                product: identity.product.or(base.product),
+                serial: identity.serial.or(base.serial),
+            }),
+            (identity, base) => identity.or(base),
+        };
+        let mut options = profile.options;
+        options.extend(self.options);
+
+        DeviceSection {
+            name: self.name,
+            profile: self.profile,
+            backend: self.backend.or(profile.backend),
+            url: self.url.or(profile.url),
+            hosts: self.hosts.or(profile.hosts),
+            port: self.port.or(profile.port),
+            ups: self.ups.or(profile.ups),
+            username: self.username.or(profile.username),
+            password_env: self.password_env.or(profile.password_env),
+            password_file: self.password_file.or(profile.password_file),
+            poll: self.poll.or(profile.poll),
+            identity,
+            options,
+        }
+    }
+
+    fn into_config(self) -> Result<DeviceConfig, ConfigError> {
+        let mut config = match &self.url {
+            Some(url) => DeviceConfig::parse_url(url)?,
+            None => DeviceConfig {
+                backend: "nut".into(),
+                ..Default::default()
+            },
+        };
+
+        if let Some(backend) = &self.backend {
+            config.backend = backend.clone();
+        }
+        let port = self.port.unwrap_or(NUT_DEFAULT_PORT);
+        if let Some(hosts) = &self.hosts {
+            config.endpoints = Endpoint::parse_list(&hosts.join(","), port)?;
+        } else if config.endpoints.is_empty()
+            && registry()
+                .get(&config.backend)
+                .is_some_and(|backend| backend.schema.endpoints)
+        {
+            config.endpoints = vec![Endpoint::new("localhost", port)];
+        }
+
+        /* the environment variable takes precedence over the file */
+        if let Some(name) = self.password_env {
+            config.options.insert("password_env".into(), name);
+        } else if let Some(path) = self.password_file {
+            config.options.insert("password_file".into(), path);
+        }
+        if self.username.is_some() {
+            config.username = self.username;
+        }
+        if self.ups.is_some() {
+            config.ups = self.ups;
+        }
+
+        if let Some(poll) = self.poll {
+            config.options.insert("poll".into(), poll.to_string());
+        }
+        if let Some(identity) = self.identity {
+            for (name, value) in [
+                ("manufacturer", identity.manufacturer),
+                ("product", identity.product),
+                ("serial", identity.serial),
+            ] {
+                if let Some(value) = value {
+                    config.options.insert(name.into(), value);
+                }
+            }
+        }
+        config.options.extend(self.options);
+
+        Ok(config)
+    }
+}
+
+/// Parse a configuration file, the configurations still need to be validated
+pub fn parse_config_file(text: &str) -> Result<Vec<NamedConfig>, ConfigError> {
+    let file: ConfigFile =
+        toml::from_str(text).map_err(|err| ConfigError::InvalidFile(err.to_string()))?;
+
+    if file.devices.is_empty() {
+        return Err(ConfigError::InvalidFile("no [[device]] defined".into()));
+    }
+
+    let mut result: Vec<NamedConfig> = Vec::new();
+    for (index, section) in file.devices.into_iter().enumerate() {
+        let name = section
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("device{}", index + 1));
+        /* the name becomes part of the device instance id */
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ConfigError::InvalidDeviceName(name));
+        }
+        if result.iter().any(|named| named.name == name) {
+            return Err(ConfigError::DuplicateDevice(name));
+        }
+
+        let section = match &section.profile {
+            Some(profile) => {
+                let base = file
+                    .profiles
+                    .get(profile)
+                    .ok_or_else(|| ConfigError::UnknownProfile(profile.clone()))?;
+                section.inherit(base)
+            }
+            None => section,
+        };
+
+        result.push(NamedConfig {
+            name,
+            config: section.into_config()?,
+        });
+    }
+    Ok(result)
+}
+
+pub fn load_config_file(path: &str) -> Result<Vec<NamedConfig>, ConfigError> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| ConfigError::InvalidFile(format!("{path}: {err}")))?;
+    parse_config_file(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let configs = parse_config_file("[[device]]\n").unwrap();
+        assert_eq!(
+            configs,
+            vec![NamedConfig {
+                name: "device1".into(),
+                config: DeviceConfig {
+                    endpoints: vec![Endpoint::new("localhost", NUT_DEFAULT_PORT)],
+                    backend: "nut".into(),
+                    ..Default::default()
+                },
+            }]
+        );
+
+        let configs = parse_config_file("[[device]]\nbackend = \"dummy\"\n").unwrap();
+        assert!(configs[0].config.endpoints.is_empty());
+    }
+
+    #[test]
+    fn settings() {
+        let text = r#"
+            [[device]]
+            name = "rack1"
+            hosts = ["nut1", "[::1]:3494"]
+            port = 3500
+            ups = "ups1"
+            username = "monitor"
+            poll = 2.5
+            identity = { product = "Rack 1", serial = "R1" }
+            options = { record = 'C:\ups.rec' }
+        "#;
+        let configs = parse_config_file(text).unwrap();
+        assert_eq!(configs[0].name, "rack1");
+        assert_eq!(
+            configs[0].config,
+            DeviceConfig {
+                endpoints: vec![Endpoint::new("nut1", 3500), Endpoint::new("::1", 3494)],
+                backend: "nut".into(),
+                username: Some("monitor".into()),
+                password: None,
+                ups: Some("ups1".into()),
+                options: DeviceConfig::parse_options(
+                    "poll=2.5;product=Rack 1;serial=R1;record=C:\\ups.rec"
+                ),
+            }
+        );
+        assert!(configs[0].config.validate().is_ok());
+    }
+
+    #[test]
+    fn multiple_devices_and_profiles() {
+        let password =
+            std::env::temp_dir().join(format!("nut_hid_{}_password", std::process::id()));
+        fs::write(&password, "secret\r\n").unwrap();
+
+        let text = format!(
+            r#"
+            [profiles.rack]
+            hosts = ["nut1", "nut2"]
+            username = "monitor"
+            password_file = '{}'
+            poll = 5
+            identity = {{ manufacturer = "Acme" }}
+            options = {{ grace = "30" }}
+
+            [[device]]
+            profile = "rack"
+            ups = "ups1"
+            identity = {{ product = "Rack 1" }}
+
+            [[device]]
+            name = "rack2"
+            profile = "rack"
+            hosts = ["nut3"]
+            ups = "ups2"
+            poll = 1
+
+            [[device]]
+            name = "lab"
+            url = "replay:?file=lab.rec"
+            "#,
+            password.display()
+        );
+        let configs = parse_config_file(&text).unwrap();
+
+        let names = configs
+            .iter()
+            .map(|named| named.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["device1", "rack2", "lab"]);
+
+        let rack1 = &configs[0].config;
+        assert_eq!(
+            rack1.endpoints,
+            vec![Endpoint::new("nut1", 3493), Endpoint::new("nut2", 3493)]
+        );
+        assert_eq!(rack1.password, None);
+        assert!(!rack1.to_url().contains("secret"));
+        assert_eq!(rack1.resolve_password(), Ok(Some("secret".into())));
+        fs::remove_file(&password).unwrap();
+        assert_eq!(rack1.ups.as_deref(), Some("ups1"));
+        assert_eq!(
+            rack1.options,
+            DeviceConfig::parse_options(&format!(
+                "poll=5;manufacturer=Acme;product=Rack 1;grace=30;password_file={}",
+                password.display()
+            ))
+        );
+
+        let rack2 = &configs[1].config;
+        assert_eq!(rack2.endpoints, vec![Endpoint::new("nut3", 3493)]);
+        assert_eq!(rack2.username.as_deref(), Some("monitor"));
+        assert_eq!(rack2.option("poll"), Some("1"));
+        assert_eq!(rack2.option("manufacturer"), Some("Acme"));
+
+        let lab = &configs[2].config;
+        assert_eq!(lab.backend, "replay");
+        assert_eq!(lab.option("file"), Some("lab.rec"));
+
+        for named in &configs {
+            assert!(named.config.validate().is_ok(), "{}", named.name);
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for text in [
+            "[[device]]\nhost = \"nut1\"\n",
+            "[[device]]\nidentity = { vendor = \"Acme\" }\n",
+            "[profiles.rack]\nhots = []\n[[device]]\n",
+            "[devices]\n",
+        ] {
+            assert!(
+                matches!(parse_config_file(text), Err(ConfigError::InvalidFile(_))),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            parse_config_file(""),
+            Err(ConfigError::InvalidFile(_))
+        ));
+        assert!(matches!(
+            parse_config_file("[[device]]\nport = \"3493\"\n"),
+            Err(ConfigError::InvalidFile(_))
+        ));
+        assert_eq!(
+            parse_config_file("[[device]]\nname = \"a\"\n[[device]]\nname = \"a\"\n"),
+            Err(ConfigError::DuplicateDevice("a".into()))
+        );
+        for name in ["", "rack 1", "rack\\1", "rack/1", "räck"] {
+            assert_eq!(
+                parse_config_file(&format!("[[device]]\nname = '{name}'\n")),
+                Err(ConfigError::InvalidDeviceName(name.into())),
+                "{name}"
+            );
+        }
+        assert_eq!(
+            parse_config_file("[[device]]\nprofile = \"rack\"\n"),
+            Err(ConfigError::UnknownProfile("rack".into()))
+        );
+        assert!(matches!(
+            parse_config_file("[[device]]\nurl = \"localhost\"\n"),
+            Err(ConfigError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            load_config_file("/nonexistent/nut_hid.toml"),
+            Err(ConfigError::InvalidFile(_))
+        ));
+    }
+}
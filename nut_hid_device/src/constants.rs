@@ -5,3 +5,8 @@ pub const NUT_HID_VERSION: u16 = 0x0101;
 pub const NUT_HID_MANUFACTURER: &str = "MLC Microline Consulting AB";
 pub const NUT_HID_SERIALNUMBER: &str = "0";
 pub const NUT_HID_PRODUCT: &str = "nut hid device";
+
+/// String indexes resolving to the identity of the device
+pub const STRING_ID_MANUFACTURER: u8 = 0x01;
+pub const STRING_ID_PRODUCT: u8 = 0x02;
+pub const STRING_ID_SERIAL: u8 = 0x03;
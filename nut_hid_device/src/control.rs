@@ -0,0 +1,314 @@
+//! Control channel changing the values of the dummy backend at runtime.
+//!
+//! The dummy backend listens on a loopback TCP port for one command per line and
+//! answers `ok` or `error: <reason>`:
+//!
+//! ```text
+//! set charge 42
+//! set runtime 30
+//! ac off
+//! status LB
+//! status shutdown_requested off
+//! fsd
+//! ```
+//!
+//! Status bits are named by their NUT `ups.status` token (`OL`, `LB`, `RB`, `CHRG`,
+//! `DISCHRG`, `OVER`, `FSD`) or as in mapping files. Every command immediately queues
+//! the input reports it changed.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::*;
+use dummy::{REPORT_ID_REMAININGCAPACITY, runtime_report};
+use log::{debug, info, warn};
+use mapping::Usage;
+use nut::PresentStatus;
+use status::StatusBit;
+
+/// Input reports changed by one command
+type Batch = Vec<(u8, Vec<u8>)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Charge in percent
+    SetCharge(f64),
+    /// Minutes until empty
+    SetRuntime(f64),
+    Ac(bool),
+    Status(StatusBit, bool),
+    /// Forced shutdown by the NUT primary
+    Fsd,
+}
+
+fn status_bit(name: &str) -> Option<StatusBit> {
+    match name {
+        "OL" => Some(StatusBit::AcPresent),
+        "LB" => Some(StatusBit::BelowRemainingCapacityLimit),
+        "RB" => Some(StatusBit::NeedReplacement),
+        "CHRG" => Some(StatusBit::Charging),
+        "DISCHRG" => Some(StatusBit::Discharging),
+        "OVER" => Some(StatusBit::Overload),
+        "FSD" => Some(StatusBit::ShutdownImminent),
+        name => StatusBit::from_name(name),
+    }
+}
+
+fn switch(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        value => Err(format!("expected on or off, not '{value}'")),
+    }
+}
+
+fn number(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) if number >= 0.0 => Ok(number),
+        _ => Err(format!("invalid number '{value}'")),
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["set", "charge", value] => {
+                let charge = number(value)?;
+                match charge <= 100.0 {
+                    true => Ok(Command::SetCharge(charge)),
+                    false => Err(format!("charge {charge} above 100")),
+                }
+            }
+            ["set", "runtime", value] => Ok(Command::SetRuntime(number(value)?)),
+            ["ac", value] => Ok(Command::Ac(switch(value)?)),
+            ["status", name] | ["status", name, _] => {
+                let bit = status_bit(name).ok_or_else(|| format!("unknown status '{name}'"))?;
+                let set = match words.get(2) {
+                    Some(value) => switch(value)?,
+                    None => true,
+                };
+                Ok(Command::Status(bit, set))
+            }
+            ["fsd"] => Ok(Command::Fsd),
+            _ => Err(format!("unknown command '{}'", line.trim())),
+        }
+    }
+}
+
+/// Status bits set by the commands so far
+#[derive(Debug, Clone)]
+pub struct ControlState {
+    bits: BTreeSet<StatusBit>,
+}
+
+impl Default for ControlState {
+    /* as reported by the dummy backend on creation */
+    fn default() -> ControlState {
+        ControlState {
+            bits: BTreeSet::from([StatusBit::AcPresent, StatusBit::BatteryPresent]),
+        }
+    }
+}
+
+impl ControlState {
+    fn set(&mut self, bit: StatusBit, set: bool) {
+        if set {
+            self.bits.insert(bit);
+        } else {
+            self.bits.remove(&bit);
+        }
+    }
+
+    /// Input reports changed by a command
+    pub fn apply(&mut self, command: &Command) -> Batch {
+        match command {
+            Command::SetCharge(charge) => {
+                return vec![(
+                    REPORT_ID_REMAININGCAPACITY,
+                    Usage::RemainingCapacity.encode(*charge),
+                )];
+            }
+            Command::SetRuntime(minutes) => {
+                return vec![runtime_report(minutes * 60.0)];
+            }
+            Command::Ac(present) => {
+                self.set(StatusBit::AcPresent, *present);
+                self.set(StatusBit::Discharging, !present);
+                if !present {
+                    self.set(StatusBit::Charging, false);
+                }
+            }
+            Command::Status(bit, set) => self.set(*bit, *set),
+            Command::Fsd => self.set(StatusBit::ShutdownImminent, true),
+        }
+        vec![PresentStatus::from_bits(&self.bits).report()]
+    }
+}
+
+/// Listening end of the control channel, the reports of the commands are received here
+///
+/// The port is released when the channel is dropped.
+pub struct Control {
+    pub address: SocketAddr,
+    pub reports: Mutex<Receiver<Batch>>,
+    stopped: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+fn serve(stream: TcpStream, state: &Mutex<ControlState>, sender: &Sender<Batch>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("Control command: {line}");
+        match Command::parse(&line) {
+            Ok(command) => {
+                let reports = state.lock().unwrap().apply(&command);
+                /* the device is gone once nobody receives */
+                if sender.send(reports).is_err() {
+                    return Ok(());
+                }
+                writeln!(writer, "ok")?;
+            }
+            Err(err) => writeln!(writer, "error: {err}")?,
+        }
+    }
+    Ok(())
+}
+
+impl Control {
+    /// Listen on a loopback port, 0 picks a free one
+    pub fn listen(port: u16) -> io::Result<Control> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let address = listener.local_addr()?;
+        info!("Listening for control commands on {address}");
+
+        let (sender, receiver) = channel();
+        let state = Arc::new(Mutex::new(ControlState::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopping = stopped.clone();
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept control connection: {err}");
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, &state, &sender) {
+                        warn!("Control connection failed: {err}");
+                    }
+                });
+            }
+        });
+
+        Ok(Control {
+            address,
+            reports: Mutex::new(receiver),
+            stopped,
+            listener: Some(listener),
+        })
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        /* wake up the accept blocking the listener thread */
+        if let Err(err) = TcpStream::connect(self.address) {
+            warn!("Failed to stop the control channel: {err}");
+            return;
+        }
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+        debug!("Stopped listening on {}", self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_releases_port() {
+        let control = Control::listen(0).unwrap();
+        let address = control.address;
+        assert!(TcpStream::connect(address).is_ok());
+
+        drop(control);
+        assert!(TcpStream::connect(address).is_err());
+        assert!(TcpListener::bind(address).is_ok());
+    }
+
+    #[test]
+    fn parse_commands() {
+        for (line, command) in [
+            ("set charge 42", Command::SetCharge(42.0)),
+            ("set runtime 7.5", Command::SetRuntime(7.5)),
+            ("ac off", Command::Ac(false)),
+            (
+                "status LB",
+                Command::Status(StatusBit::BelowRemainingCapacityLimit, true),
+            ),
+            (
+                "status shutdown_requested off",
+                Command::Status(StatusBit::ShutdownRequested, false),
+            ),
+            (
+                "status PresentStatus.Overload on",
+                Command::Status(StatusBit::Overload, true),
+            ),
+            ("  fsd ", Command::Fsd),
+        ] {
+            assert_eq!(Command::parse(line), Ok(command), "{line}");
+        }
+
+        for line in [
+            "",
+            "set charge",
+            "set charge 101",
+            "set charge -1",
+            "set voltage 12",
+            "ac maybe",
+            "status XX",
+            "status LB maybe",
+            "fsd now",
+        ] {
+            assert!(Command::parse(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn apply_commands() {
+        let mut state = ControlState::default();
+        assert_eq!(
+            state.apply(&Command::SetCharge(42.4)),
+            [(REPORT_ID_REMAININGCAPACITY, vec![42])]
+        );
+
+        let expected = PresentStatus::from_bits(&BTreeSet::from([
+            StatusBit::BatteryPresent,
+            StatusBit::Discharging,
+            StatusBit::ShutdownImminent,
+        ]));
+        state.apply(&Command::Status(StatusBit::Charging, true));
+        state.apply(&Command::Ac(false));
+        assert_eq!(state.apply(&Command::Fsd), [expected.report()]);
+    }
+}
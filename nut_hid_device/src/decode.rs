@@ -0,0 +1,461 @@
+//! Decoding of raw reports into named values, for logs and tests.
+//!
+//! The layout and units come from the descriptor, the names from the Power Device and
+//! Battery System usage pages. Every field also has a path in the style of NUT's
+//! usbhid-ups, the names of its collections and its usage, e.g.
+//! `UPS.PowerSummary.PresentStatus.ACPresent`.
+
+use std::fmt;
+
+use super::*;
+use descriptor::{
+    AC_PRESENT, BATTERY_SYSTEM, CAPACITY_MODE, CHARGING, DESIGN_CAPACITY, DISCHARGING,
+    FULL_CHARGE_CAPACITY, Field, POWER_DEVICE, PRESENT_STATUS, REMAINING_CAPACITY,
+    RUN_TIME_TO_EMPTY, usage,
+};
+
+/* collections whose name is part of the value names */
+const STATUS_COLLECTIONS: &[u32] = &[PRESENT_STATUS, usage(POWER_DEVICE, 0x03)];
+
+#[rustfmt::skip]
+const NAMES: &[(u32, &str)] = &[
+    (PRESENT_STATUS, "PresentStatus"),
+    (usage(POWER_DEVICE, 0x03), "ChangedStatus"),
+    (usage(POWER_DEVICE, 0x04), "UPS"),
+    (usage(POWER_DEVICE, 0x24), "PowerSummary"),
+    (usage(POWER_DEVICE, 0x30), "Voltage"),
+    (usage(POWER_DEVICE, 0x31), "Current"),
+    (usage(POWER_DEVICE, 0x40), "ConfigVoltage"),
+    (usage(POWER_DEVICE, 0x55), "DelayBeforeReboot"),
+    (usage(POWER_DEVICE, 0x56), "DelayBeforeStartup"),
+    (usage(POWER_DEVICE, 0x57), "DelayBeforeShutdown"),
+    (usage(POWER_DEVICE, 0x5A), "AudibleAlarmControl"),
+    (usage(POWER_DEVICE, 0x61), "Good"),
+    (usage(POWER_DEVICE, 0x62), "InternalFailure"),
+    (usage(POWER_DEVICE, 0x65), "Overload"),
+    (usage(POWER_DEVICE, 0x68), "ShutdownRequested"),
+    (usage(POWER_DEVICE, 0x69), "ShutdownImminent"),
+    (usage(POWER_DEVICE, 0x73), "CommunicationLost"),
+    (usage(POWER_DEVICE, 0xFD), "iManufacturer"),
+    (usage(POWER_DEVICE, 0xFE), "iProduct"),
+    (usage(POWER_DEVICE, 0xFF), "iSerialNumber"),
+    (usage(BATTERY_SYSTEM, 0x29), "RemainingCapacityLimit"),
+    (usage(BATTERY_SYSTEM, 0x2A), "RemainingTimeLimit"),
+    (CAPACITY_MODE, "CapacityMode"),
+    (usage(BATTERY_SYSTEM, 0x42), "BelowRemainingCapacityLimit"),
+    (usage(BATTERY_SYSTEM, 0x43), "RemainingTimeLimitExpired"),
+    (CHARGING, "Charging"),
+    (DISCHARGING, "Discharging"),
+    (usage(BATTERY_SYSTEM, 0x46), "FullyCharged"),
+    (usage(BATTERY_SYSTEM, 0x47), "FullyDischarged"),
+    (usage(BATTERY_SYSTEM, 0x4B), "NeedReplacement"),
+    (REMAINING_CAPACITY, "RemainingCapacity"),
+    (FULL_CHARGE_CAPACITY, "FullChargeCapacity"),
+    (RUN_TIME_TO_EMPTY, "RunTimeToEmpty"),
+    (usage(BATTERY_SYSTEM, 0x69), "AverageTimeToEmpty"),
+    (usage(BATTERY_SYSTEM, 0x6A), "AverageTimeToFull"),
+    (DESIGN_CAPACITY, "DesignCapacity"),
+    (usage(BATTERY_SYSTEM, 0x85), "ManufacturerDate"),
+    (usage(BATTERY_SYSTEM, 0x89), "iDeviceChemistry"),
+    (usage(BATTERY_SYSTEM, 0x8B), "Rechargable"),
+    (usage(BATTERY_SYSTEM, 0x8C), "WarningCapacityLimit"),
+    (usage(BATTERY_SYSTEM, 0x8D), "CapacityGranularity1"),
+    (usage(BATTERY_SYSTEM, 0x8E), "CapacityGranularity2"),
+    (usage(BATTERY_SYSTEM, 0x8F), "iOEMInformation"),
+    (AC_PRESENT, "ACPresent"),
+    (usage(BATTERY_SYSTEM, 0xD1), "BatteryPresent"),
+    (usage(BATTERY_SYSTEM, 0xDB), "VoltageNotRegulated"),
+];
+
+/* capacities are reported in percent, CapacityMode 2 */
+const PERCENT: &[u32] = &[
+    usage(BATTERY_SYSTEM, 0x29),
+    REMAINING_CAPACITY,
+    FULL_CHARGE_CAPACITY,
+    DESIGN_CAPACITY,
+    usage(BATTERY_SYSTEM, 0x8C),
+    usage(BATTERY_SYSTEM, 0x8D),
+    usage(BATTERY_SYSTEM, 0x8E),
+];
+
+/// Symbol of a unit and the exponent of its base unit relative to SI
+fn unit(field: &Field) -> (&'static str, i32) {
+    match field.unit {
+        0x0000_1001 => ("s", 0),
+        0x0010_0001 => ("A", 0),
+        /* SI linear volts are based on centimeters and grams */
+        0x00F0_D121 => ("V", -7),
+        0 if field.usage.is_some_and(|usage| PERCENT.contains(&usage)) => ("%", 0),
+        _ => ("", 0),
+    }
+}
+
+pub fn usage_name(usage: u32) -> String {
+    match NAMES.iter().find(|(known, _)| *known == usage) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:02X}:{:02X}", usage >> 16, usage & 0xFFFF),
+    }
+}
+
+/// Path of a field, `None` for padding
+pub fn path(field: &Field) -> Option<String> {
+    let usage = field.usage?;
+    let mut names = field
+        .collections
+        .iter()
+        .map(|collection| usage_name(*collection))
+        .collect::<Vec<_>>();
+    names.push(usage_name(usage));
+    Some(names.join("."))
+}
+
+/* a full path or its last components, compared case insensitively like usbhid-ups */
+fn path_matches(path: &str, query: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    let query = query.to_ascii_lowercase();
+    path == query || path.ends_with(&format!(".{query}"))
+}
+
+/// First field of the descriptor matching a path
+pub fn find(descriptor: &[u8], query: &str) -> Option<Field> {
+    descriptor::fields(descriptor)
+        .into_iter()
+        .find(|field| path(field).is_some_and(|path| path_matches(&path, query)))
+}
+
+/// Current value of a path, from the feature report if there is one
+pub fn lookup(data: &DeviceData, query: &str) -> Option<Value> {
+    let mut fields = descriptor::fields(&data.report_descriptor)
+        .into_iter()
+        .filter(|field| path(field).is_some_and(|path| path_matches(&path, query)))
+        .collect::<Vec<_>>();
+    fields.sort_by_key(|field| field.report_type != ReportType::Feature);
+
+    fields.iter().find_map(|field| {
+        let report = data.reports.get(field.report_type, field.report_id)?;
+        value(field, report)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    Flag(bool),
+    Number(f64),
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reading::Flag(value) => write!(f, "{value}"),
+            Reading::Number(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A named value of a report, the alternate format shows the path instead of the name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub name: String,
+    pub path: String,
+    pub reading: Reading,
+    pub unit: &'static str,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if f.alternate() {
+            &self.path
+        } else {
+            &self.name
+        };
+        write!(f, "{name} = {}", self.reading)?;
+        if !self.unit.is_empty() {
+            write!(f, " {}", self.unit)?;
+        }
+        Ok(())
+    }
+}
+
+/* little endian bits, sign extended for fields with a negative minimum */
+fn extract(report: &[u8], field: &Field) -> Option<i64> {
+    if field.size == 0 || field.size > 32 {
+        return None;
+    }
+    let mut value = 0u64;
+    for bit in 0..field.size {
+        let position = (field.offset + bit) as usize;
+        let byte = report.get(position / 8)?;
+        value |= ((byte >> (position % 8) & 1) as u64) << bit;
+    }
+    if field.logical_minimum < 0 {
+        let shift = 64 - field.size;
+        return Some(((value << shift) as i64) >> shift);
+    }
+    Some(value as i64)
+}
+
+/// Named values of a report, fields beyond the end of the report are left out
+pub fn decode(
+    descriptor: &[u8],
+    report_type: ReportType,
+    report_id: u8,
+    report: &[u8],
+) -> Vec<Value> {
+    descriptor::fields(descriptor)
+        .iter()
+        .filter(|field| field.report_type == report_type && field.report_id == report_id)
+        .filter_map(|field| value(field, report))
+        .collect()
+}
+
+fn value(field: &Field, report: &[u8]) -> Option<Value> {
+    let usage = field.usage?;
+    let raw = extract(report, field)?;
+
+    let mut name = usage_name(usage);
+    if let Some(collection) = field.collection
+        && STATUS_COLLECTIONS.contains(&collection)
+    {
+        name = format!("{}.{name}", usage_name(collection));
+    }
+
+    let (unit, base) = unit(field);
+    let reading = if field.size == 1 && unit.is_empty() {
+        Reading::Flag(raw != 0)
+    } else {
+        let exponent = field.unit_exponent + base;
+        let value = raw as f64 * 10f64.powi(exponent);
+        /* drop float noise below the resolution of the field */
+        let decimals = 10f64.powi((-exponent).max(0));
+        Reading::Number((value * decimals).round() / decimals)
+    };
+    Some(Value {
+        name,
+        path: path(field)?,
+        reading,
+        unit,
+    })
+}
+
+/// One line description of a report with the full paths, for logging
+///
+/// Cleared flags are left out, a status report would otherwise list every flag.
+pub fn describe(
+    descriptor: &[u8],
+    report_type: ReportType,
+    report_id: u8,
+    report: &[u8],
+) -> String {
+    let values = decode(descriptor, report_type, report_id, report);
+    if values.is_empty() {
+        return format!("report {report_id} {report:02x?}");
+    }
+    let set = values
+        .iter()
+        .filter(|value| value.reading != Reading::Flag(false))
+        .map(|value| format!("{value:#}"))
+        .collect::<Vec<_>>();
+    if set.is_empty() {
+        return format!("report {report_id} without flags");
+    }
+    set.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(values: &[Value]) -> Vec<String> {
+        values.iter().map(Value::to_string).collect()
+    }
+
+    #[test]
+    fn decode_ups_reports() {
+        let descriptor = dummy::UPS_REPORT_DESCRIPTOR;
+        assert_eq!(
+            names(&decode(
+                descriptor,
+                ReportType::Input,
+                dummy::REPORT_ID_REMAININGCAPACITY,
+                &[80]
+            )),
+            ["RemainingCapacity = 80 %"]
+        );
+        assert_eq!(
+            names(&decode(
+                descriptor,
+                ReportType::Feature,
+                dummy::REPORT_ID_VOLTAGE,
+                &1234u16.to_le_bytes()
+            )),
+            ["Voltage = 12.34 V"]
+        );
+        assert_eq!(
+            names(&decode(
+                descriptor,
+                ReportType::Feature,
+                dummy::REPORT_ID_REMAINTIMELIMIT,
+                &120u16.to_le_bytes()
+            )),
+            ["RemainingTimeLimit = 120 s"]
+        );
+    }
+
+    #[test]
+    fn decode_present_status() {
+        let values = decode(
+            dummy::UPS_REPORT_DESCRIPTOR,
+            ReportType::Input,
+            dummy::REPORT_ID_PRESENTSTATUS,
+            &[0x05, 0x00],
+        );
+        let set = values
+            .iter()
+            .filter(|value| value.reading == Reading::Flag(true))
+            .map(Value::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            set,
+            [
+                "PresentStatus.Charging = true",
+                "PresentStatus.ACPresent = true"
+            ]
+        );
+        assert!(names(&values).contains(&"PresentStatus.Discharging = false".to_string()));
+    }
+
+    #[test]
+    fn ups_paths() {
+        use ReportType::{Feature, Input};
+
+        let status = [
+            "Charging",
+            "Discharging",
+            "ACPresent",
+            "BatteryPresent",
+            "BelowRemainingCapacityLimit",
+            "RemainingTimeLimitExpired",
+            "NeedReplacement",
+            "VoltageNotRegulated",
+            "FullyCharged",
+            "FullyDischarged",
+            "ShutdownRequested",
+            "ShutdownImminent",
+            "CommunicationLost",
+            "Overload",
+        ];
+        let mut expected = vec![
+            (Feature, 1, "iProduct"),
+            (Feature, 1, "iSerialNumber"),
+            (Feature, 1, "iManufacturer"),
+            (Feature, 22, "CapacityMode"),
+            (Feature, 14, "FullChargeCapacity"),
+            (Feature, 23, "DesignCapacity"),
+            (Input, 12, "RemainingCapacity"),
+            (Feature, 12, "RemainingCapacity"),
+            (Feature, 17, "RemainingCapacityLimit"),
+            (Feature, 9, "ManufacturerDate"),
+            (Feature, 8, "RemainingTimeLimit"),
+            (Input, 13, "RunTimeToEmpty"),
+            (Feature, 13, "RunTimeToEmpty"),
+            (Input, 18, "DelayBeforeShutdown"),
+            (Feature, 18, "DelayBeforeShutdown"),
+        ]
+        .into_iter()
+        .map(|(report_type, id, name)| (report_type, id, format!("UPS.PowerSummary.{name}")))
+        .collect::<Vec<_>>();
+        for name in status {
+            for report_type in [Input, Feature] {
+                expected.push((
+                    report_type,
+                    7,
+                    format!("UPS.PowerSummary.PresentStatus.{name}"),
+                ));
+            }
+        }
+
+        /* padding has no path */
+        let paths = descriptor::fields(nut::UPS_REPORT_DESCRIPTOR)
+            .iter()
+            .filter_map(|field| Some((field.report_type, field.report_id, path(field)?)))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn find_paths() {
+        let descriptor = nut::UPS_REPORT_DESCRIPTOR;
+        for query in [
+            "UPS.PowerSummary.RemainingCapacity",
+            "ups.powersummary.remainingcapacity",
+            "PowerSummary.RemainingCapacity",
+            "RemainingCapacity",
+        ] {
+            let field = find(descriptor, query).unwrap();
+            assert_eq!(field.usage, Some(usage(0x85, 0x66)), "{query}");
+        }
+        assert_eq!(
+            find(descriptor, "PresentStatus.ACPresent").unwrap().usage,
+            Some(usage(0x85, 0xD0))
+        );
+        assert!(find(descriptor, "Capacity").is_none());
+        assert!(find(descriptor, "UPS.Voltage").is_none());
+    }
+
+    #[test]
+    fn lookup_values() {
+        let mut data = nut::new_nut_device_data();
+        data.reports.set(ReportType::Input, 12, [20]);
+        data.reports.set(ReportType::Feature, 12, [80]);
+
+        let value = lookup(&data, "UPS.PowerSummary.RemainingCapacity").unwrap();
+        assert_eq!(value.reading, Reading::Number(80.0));
+        assert_eq!(
+            format!("{value:#}"),
+            "UPS.PowerSummary.RemainingCapacity = 80 %"
+        );
+        assert!(lookup(&data, "UPS.PowerSummary.Voltage").is_none());
+
+        assert_eq!(
+            describe(&data.report_descriptor, ReportType::Input, 12, &[20]),
+            "UPS.PowerSummary.RemainingCapacity = 20 %"
+        );
+        assert_eq!(
+            describe(&data.report_descriptor, ReportType::Input, 7, &[0x05, 0x00]),
+            "UPS.PowerSummary.PresentStatus.Charging = true, UPS.PowerSummary.PresentStatus.ACPresent = true"
+        );
+        assert_eq!(
+            describe(&data.report_descriptor, ReportType::Input, 7, &[0, 0]),
+            "report 7 without flags"
+        );
+    }
+
+    #[test]
+    fn signed_values() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x05, 0x84,       // USAGE_PAGE (Power Device)
+            0x85, 0x12,       // REPORT_ID (18)
+            0x15, 0xFF,       // LOGICAL_MINIMUM (-1)
+            0x26, 0xFF, 0x7F, // LOGICAL_MAXIMUM (32767)
+            0x75, 0x10,       // REPORT_SIZE (16)
+            0x95, 0x01,       // REPORT_COUNT (1)
+            0x66, 0x01, 0x10, // UNIT (Seconds)
+            0x09, 0x57,       // USAGE (DelayBeforeShutdown)
+            0xB1, 0xA2,       // FEATURE
+        ];
+        assert_eq!(
+            names(&decode(
+                &descriptor,
+                ReportType::Feature,
+                0x12,
+                &[0xff, 0xff]
+            )),
+            ["DelayBeforeShutdown = -1 s"]
+        );
+        assert_eq!(
+            describe(&descriptor, ReportType::Input, 0x12, &[1]),
+            "report 18 [01]"
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//! Parsing of HID report descriptors.
+//!
+//! Only what the backends need: walking the items and working out which fields and
+//! reports a descriptor declares.
+
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// Item tags, with the size bits of the prefix cleared
+pub const TAG_INPUT: u8 = 0x80;
+pub const TAG_OUTPUT: u8 = 0x90;
+pub const TAG_COLLECTION: u8 = 0xA0;
+pub const TAG_FEATURE: u8 = 0xB0;
+pub const TAG_END_COLLECTION: u8 = 0xC0;
+pub const TAG_USAGE_PAGE: u8 = 0x04;
+pub const TAG_LOGICAL_MINIMUM: u8 = 0x14;
+pub const TAG_LOGICAL_MAXIMUM: u8 = 0x24;
+pub const TAG_UNIT_EXPONENT: u8 = 0x54;
+pub const TAG_UNIT: u8 = 0x64;
+pub const TAG_REPORT_SIZE: u8 = 0x74;
+pub const TAG_REPORT_ID: u8 = 0x84;
+pub const TAG_REPORT_COUNT: u8 = 0x94;
+pub const TAG_PUSH: u8 = 0xA4;
+pub const TAG_POP: u8 = 0xB4;
+pub const TAG_USAGE: u8 = 0x08;
+pub const TAG_USAGE_MINIMUM: u8 = 0x18;
+pub const TAG_USAGE_MAXIMUM: u8 = 0x28;
+pub const TAG_STRING_INDEX: u8 = 0x78;
+
+/// Usage page and id combined into one extended usage
+pub const fn usage(page: u16, id: u16) -> u32 {
+    (page as u32) << 16 | id as u32
+}
+
+/// Usage page of the power device usages
+pub const POWER_DEVICE: u16 = 0x84;
+/// Usage page of the battery system usages
+pub const BATTERY_SYSTEM: u16 = 0x85;
+
+/* usages Windows reads from a battery */
+pub const PRESENT_STATUS: u32 = usage(POWER_DEVICE, 0x02);
+pub const CHARGING: u32 = usage(BATTERY_SYSTEM, 0x44);
+pub const DISCHARGING: u32 = usage(BATTERY_SYSTEM, 0x45);
+pub const CAPACITY_MODE: u32 = usage(BATTERY_SYSTEM, 0x2C);
+pub const REMAINING_CAPACITY: u32 = usage(BATTERY_SYSTEM, 0x66);
+pub const FULL_CHARGE_CAPACITY: u32 = usage(BATTERY_SYSTEM, 0x67);
+pub const RUN_TIME_TO_EMPTY: u32 = usage(BATTERY_SYSTEM, 0x68);
+pub const DESIGN_CAPACITY: u32 = usage(BATTERY_SYSTEM, 0x83);
+pub const AC_PRESENT: u32 = usage(BATTERY_SYSTEM, 0xD0);
+
+const LONG_ITEM: u8 = 0xFE;
+
+/// A short item of a descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Item<'a> {
+    pub tag: u8,
+    pub data: &'a [u8],
+}
+
+impl Item<'_> {
+    /// Data as an unsigned little endian value
+    pub fn value(&self) -> u32 {
+        self.data
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | *byte as u32)
+    }
+
+    /// Data as a sign extended little endian value
+    pub fn signed(&self) -> i32 {
+        match self.data.len() {
+            0 => 0,
+            size => {
+                let shift = 32 - 8 * size as u32;
+                ((self.value() << shift) as i32) >> shift
+            }
+        }
+    }
+}
+
+/// Short items of a descriptor in order, long items are skipped and a truncated item
+/// ends the iteration
+pub fn items(descriptor: &[u8]) -> impl Iterator<Item = Item<'_>> {
+    let mut index = 0;
+    std::iter::from_fn(move || {
+        loop {
+            let prefix = *descriptor.get(index)?;
+            /* long items carry their size in the next byte */
+            if prefix == LONG_ITEM {
+                index += 3 + *descriptor.get(index + 1)? as usize;
+                continue;
+            }
+
+            let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+            let data = descriptor.get(index + 1..index + 1 + size)?;
+            index += 1 + size;
+            return Some(Item {
+                tag: prefix & 0xFC,
+                data,
+            });
+        }
+    })
+}
+
+/// A value in a report, padding has no usage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub report_type: ReportType,
+    pub report_id: u8,
+    /// Extended usage, see `usage`
+    pub usage: Option<u32>,
+    /// Usage of the innermost collection
+    pub collection: Option<u32>,
+    /// Usages of the enclosing collections, outermost first
+    pub collections: Vec<u32>,
+    /// Position in bits, after the report id
+    pub offset: u32,
+    pub size: u32,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    /// Unit system and exponents, 0 for none
+    pub unit: u32,
+    pub unit_exponent: i32,
+    pub string_index: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Globals {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    /// Logical maximum read as unsigned
+    logical_maximum_unsigned: i32,
+    unit: u32,
+    unit_exponent: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+#[derive(Debug, Default)]
+struct Locals {
+    usages: Vec<u32>,
+    usage_minimum: Option<u32>,
+    string_index: Option<u32>,
+}
+
+impl Locals {
+    /* a 32 bit usage carries its own page */
+    fn usage(item: &Item, globals: &Globals) -> u32 {
+        match item.data.len() {
+            4 => item.value(),
+            _ => usage(globals.usage_page, item.value() as u16),
+        }
+    }
+}
+
+/// Every field of the descriptor, in report order
+pub fn fields(descriptor: &[u8]) -> Vec<Field> {
+    let mut fields = Vec::new();
+    let mut offsets = BTreeMap::<(ReportType, u8), u32>::new();
+    let mut globals = Globals::default();
+    let mut locals = Locals::default();
+    let mut stack = Vec::new();
+    let mut collections = Vec::new();
+
+    for item in items(descriptor) {
+        let report_type = match item.tag {
+            TAG_INPUT => ReportType::Input,
+            TAG_OUTPUT => ReportType::Output,
+            TAG_FEATURE => ReportType::Feature,
+            TAG_COLLECTION => {
+                collections.push(locals.usages.first().copied());
+                locals = Locals::default();
+                continue;
+            }
+            TAG_END_COLLECTION => {
+                collections.pop();
+                locals = Locals::default();
+                continue;
+            }
+            TAG_USAGE_PAGE => {
+                globals.usage_page = item.value() as u16;
+                continue;
+            }
+            TAG_LOGICAL_MINIMUM => {
+                globals.logical_minimum = item.signed();
+                continue;
+            }
+            TAG_LOGICAL_MAXIMUM => {
+                globals.logical_maximum = item.signed();
+                globals.logical_maximum_unsigned = item.value() as i32;
+                continue;
+            }
+            TAG_UNIT => {
+                globals.unit = item.value();
+                continue;
+            }
+            TAG_UNIT_EXPONENT => {
+                /* a signed nibble */
+                globals.unit_exponent = ((item.value() as i32) << 28) >> 28;
+                continue;
+            }
+            TAG_REPORT_SIZE => {
+                globals.report_size = item.value();
+                continue;
+            }
+            TAG_REPORT_COUNT => {
+                globals.report_count = item.value();
+                continue;
+            }
+            TAG_REPORT_ID => {
+                globals.report_id = item.value() as u8;
+                continue;
+            }
+            TAG_PUSH => {
+                stack.push(globals);
+                continue;
+            }
+            TAG_POP => {
+                globals = stack.pop().unwrap_or_default();
+                continue;
+            }
+            TAG_USAGE => {
+                locals.usages.push(Locals::usage(&item, &globals));
+                continue;
+            }
+            TAG_USAGE_MINIMUM => {
+                locals.usage_minimum = Some(Locals::usage(&item, &globals));
+                continue;
+            }
+            TAG_USAGE_MAXIMUM => {
+                let maximum = Locals::usage(&item, &globals);
+                if let Some(minimum) = locals.usage_minimum.take() {
+                    locals.usages.extend(minimum..=maximum);
+                }
+                continue;
+            }
+            TAG_STRING_INDEX => {
+                locals.string_index = Some(item.value());
+                continue;
+            }
+            _ => continue,
+        };
+
+        /* a maximum below the minimum only makes sense unsigned */
+        let logical_maximum = if globals.logical_maximum < globals.logical_minimum {
+            globals.logical_maximum_unsigned
+        } else {
+            globals.logical_maximum
+        };
+
+        let offset = offsets.entry((report_type, globals.report_id)).or_default();
+        for index in 0..globals.report_count as usize {
+            /* the last usage repeats for the remaining values */
+            let usage = locals.usages.get(index).or(locals.usages.last()).copied();
+            fields.push(Field {
+                report_type,
+                report_id: globals.report_id,
+                usage,
+                collection: collections.last().copied().flatten(),
+                collections: collections.iter().flatten().copied().collect(),
+                offset: *offset,
+                size: globals.report_size,
+                logical_minimum: globals.logical_minimum,
+                logical_maximum,
+                unit: globals.unit,
+                unit_exponent: globals.unit_exponent,
+                string_index: locals.string_index,
+            });
+            *offset += globals.report_size;
+        }
+        locals = Locals::default();
+    }
+    fields
+}
+
+/// Size in bytes of every report the descriptor declares, without the report id
+pub fn report_sizes(descriptor: &[u8]) -> BTreeMap<(ReportType, u8), usize> {
+    let mut sizes = BTreeMap::new();
+    for field in fields(descriptor) {
+        let bits = field.offset + field.size;
+        let size = sizes
+            .entry((field.report_type, field.report_id))
+            .or_default();
+        *size = bits.div_ceil(8).max(*size as u32) as usize;
+    }
+    sizes
+}
+
+/// Whether the descriptor declares report ids, without them every report has id 0
+pub fn uses_report_ids(descriptor: &[u8]) -> bool {
+    items(descriptor).any(|item| item.tag == TAG_REPORT_ID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_items() {
+        /* USAGE_PAGE, a long item, a 16 bit LOGICAL_MAXIMUM and a truncated item */
+        let descriptor = [0x05, 0x84, 0xFE, 0x01, 0x10, 0xAA, 0x26, 0x85, 0x01, 0x75];
+        let items = items(&descriptor).collect::<Vec<_>>();
+        assert_eq!(
+            items,
+            [
+                Item {
+                    tag: 0x04,
+                    data: &[0x84]
+                },
+                Item {
+                    tag: 0x24,
+                    data: &[0x85, 0x01]
+                },
+            ]
+        );
+        assert_eq!(items[1].value(), 0x185);
+    }
+
+    #[test]
+    fn parse_fields() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x05, 0x84,       // USAGE_PAGE (Power Device)
+            0x09, 0x04,       // USAGE (UPS)
+            0xA1, 0x01,       // COLLECTION (Application)
+            0x85, 0x01,       //   REPORT_ID (1)
+            0x15, 0xFF,       //   LOGICAL_MINIMUM (-1)
+            0x26, 0xFF, 0x7F, //   LOGICAL_MAXIMUM (32767)
+            0x66, 0x01, 0x10, //   UNIT (Seconds)
+            0x55, 0x0E,       //   UNIT_EXPONENT (-2)
+            0x75, 0x10,       //   REPORT_SIZE (16)
+            0x95, 0x01,       //   REPORT_COUNT (1)
+            0x09, 0x57,       //   USAGE (DelayBeforeShutdown)
+            0xB1, 0xA2,       //   FEATURE
+            0x15, 0x00,       //   LOGICAL_MINIMUM (0)
+            0x25, 0xFF,       //   LOGICAL_MAXIMUM (255)
+            0x75, 0x08,       //   REPORT_SIZE (8)
+            0x09, 0xFE,       //   USAGE (iProduct)
+            0x79, 0x02,       //   STRING_INDEX (2)
+            0xB1, 0x23,       //   FEATURE
+            0x09, 0x02,       //   USAGE (PresentStatus)
+            0xA1, 0x02,       //   COLLECTION (Logical)
+            0x05, 0x85,       //     USAGE_PAGE (Battery System)
+            0x19, 0x44,       //     USAGE_MINIMUM (Charging)
+            0x29, 0x45,       //     USAGE_MAXIMUM (Discharging)
+            0x75, 0x01,       //     REPORT_SIZE (1)
+            0x95, 0x03,       //     REPORT_COUNT (3)
+            0x81, 0x02,       //     INPUT
+            0xC0,             //   END_COLLECTION
+            0xC0,             // END_COLLECTION
+        ];
+        let fields = fields(&descriptor);
+        assert_eq!(fields.len(), 5);
+
+        assert_eq!(
+            fields[0],
+            Field {
+                report_type: ReportType::Feature,
+                report_id: 1,
+                usage: Some(usage(0x84, 0x57)),
+                collection: Some(usage(0x84, 0x04)),
+                collections: vec![usage(0x84, 0x04)],
+                offset: 0,
+                size: 16,
+                logical_minimum: -1,
+                logical_maximum: 32767,
+                unit: 0x1001,
+                unit_exponent: -2,
+                string_index: None,
+            }
+        );
+        assert_eq!(fields[1].offset, 16);
+        assert_eq!(fields[1].logical_maximum, 255);
+        assert_eq!(fields[1].string_index, Some(2));
+
+        let status = &fields[2..];
+        assert_eq!(
+            status.iter().map(|field| field.usage).collect::<Vec<_>>(),
+            [
+                Some(usage(0x85, 0x44)),
+                Some(usage(0x85, 0x45)),
+                Some(usage(0x85, 0x45))
+            ]
+        );
+        assert!(
+            status
+                .iter()
+                .all(|field| field.report_type == ReportType::Input
+                    && field.collection == Some(usage(0x84, 0x02)))
+        );
+        assert_eq!(status[2].offset, 2);
+    }
+
+    #[test]
+    fn sizes() {
+        #[rustfmt::skip]
+        let descriptor = [
+            0x75, 0x08,       // REPORT_SIZE (8)
+            0x95, 0x01,       // REPORT_COUNT (1)
+            0x85, 0x01,       // REPORT_ID (1)
+            0x81, 0x02,       // INPUT
+            0xB1, 0x02,       // FEATURE
+            0xA4,             // PUSH
+            0x75, 0x01,       //   REPORT_SIZE (1)
+            0x95, 0x0A,       //   REPORT_COUNT (10)
+            0xB1, 0x02,       //   FEATURE
+            0xB4,             // POP
+            0x85, 0x02,       // REPORT_ID (2)
+            0x96, 0x03, 0x00, // REPORT_COUNT (3)
+            0x91, 0x02,       // OUTPUT
+        ];
+        assert_eq!(
+            report_sizes(&descriptor),
+            BTreeMap::from([
+                ((ReportType::Input, 1), 1),
+                ((ReportType::Output, 2), 3),
+                ((ReportType::Feature, 1), 3),
+            ])
+        );
+        assert!(uses_report_ids(&descriptor));
+
+        /* without report ids everything is report 0 */
+        let descriptor = [0x75, 0x10, 0x95, 0x01, 0x81, 0x02];
+        assert_eq!(
+            report_sizes(&descriptor),
+            BTreeMap::from([((ReportType::Input, 0), 2)])
+        );
+        assert!(!uses_report_ids(&descriptor));
+    }
+}
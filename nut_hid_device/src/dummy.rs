@@ -1,17 +1,19 @@
-use log::info;
-use std::collections::{HashMap, VecDeque};
-use std::sync::{Mutex, RwLock};
-use std::thread;
+use log::{debug, info};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use super::*;
 use binary_serde::recursive_array::RecursiveArray;
-use binary_serde::{BinarySerde, BitfieldBitOrder, Endianness, binary_serde_bitfield};
+use binary_serde::{BinarySerde, Endianness};
+use clock::{Clock, SystemClock};
 use constants::*;
+use control::Control;
+use nut::PresentStatus;
+use scenario::{Scenario, Simulation};
+use status::StatusBit;
 
-pub const STRING_ID_MANUFACTURER: u8 = 0x01;
-pub const STRING_ID_PRODUCT: u8 = 0x02;
-pub const STRING_ID_SERIAL: u8 = 0x03;
 pub const STRING_ID_DEVICECHEMISTRY: u8 = 0x04;
 pub const STRING_ID_OEMVENDOR: u8 = 0x05;
 
@@ -240,54 +242,68 @@ pub const UPS_REPORT_DESCRIPTOR: &[u8] = &[
     0xC0        // END_COLLECTION
 ];
 
-#[derive(Debug, Default, PartialEq, Eq)]
-#[binary_serde_bitfield(order = BitfieldBitOrder::LsbFirst)]
-struct PresentStatus {
-    #[bits(1)]
-    charging: bool, // bit 0x00
-    #[bits(1)]
-    discharging: bool, // bit 0x01
-    #[bits(1)]
-    ac_present: bool, // bit 0x02
-    #[bits(1)]
-    battery_present: bool, // bit 0x03
-    #[bits(1)]
-    below_remaining_capacity_limit: bool, // bit 0x04
-    #[bits(1)]
-    temaining_time_limit_expired: bool, // bit 0x05
-    #[bits(1)]
-    need_replacement: bool, // bit 0x06
-    #[bits(1)]
-    voltage_not_regulated: bool, // bit 0x07
-
-    #[bits(1)]
-    fully_charged: bool, // bit 0x08
-    #[bits(1)]
-    fully_discharged: bool, // bit 0x09
-    #[bits(1)]
-    shutdown_requested: bool, // bit 0x0A
-    #[bits(1)]
-    shutdown_imminent: bool, // bit 0x0B
-    #[bits(1)]
-    communication_lost: bool, // bit 0x0C
-    #[bits(1)]
-    overload: bool, // bit 0x0D
-    #[bits(1)]
-    unused1: bool,
-    #[bits(1)]
-    unused2: bool,
-}
-
 #[derive(Debug, Default, BinarySerde, PartialEq, Eq)]
+/* in the order of the descriptor */
 struct Identification {
     i_product: u8,
-    i_manufacturer: u8,
     i_serial: u8,
+    i_manufacturer: u8,
 }
 
+/// Charge reported by the dummy ups, in percent, one step every read
+const CHARGE_CYCLE: [u8; 6] = [80, 70, 60, 50, 60, 70];
+
 pub struct DummyDevice {
     device: RwLock<DeviceData>,
     pending: Mutex<VecDeque<(u8, Vec<u8>)>>,
+    /// Steps of the charge cycle read so far
+    cycle: Mutex<usize>,
+    /// Scenario run instead of the charge cycle
+    simulation: Option<Mutex<Simulation>>,
+    /// Speed of the scenario relative to real time, 0 runs it without delays
+    speed: f64,
+    /// Commands changing the values instead of the charge cycle
+    control: Option<Control>,
+    clock: Arc<dyn Clock>,
+}
+
+impl DummyDevice {
+    fn simulate(&self, simulation: &Mutex<Simulation>) -> Option<(u8, Vec<u8>)> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(report) = pending.pop_front() {
+            return Some(report);
+        }
+
+        let mut simulation = simulation.lock().unwrap();
+        if simulation.elapsed().is_some() && self.speed > 0.0 {
+            self.clock
+                .sleep(simulation.step_duration().div_f64(self.speed));
+        }
+        let Some(reports) = simulation.step() else {
+            debug!("Scenario finished");
+            return None;
+        };
+
+        self.device.write().unwrap().publish(&mut pending, reports);
+        pending.pop_front()
+    }
+
+    /// Address of the control channel, if enabled
+    pub fn control_address(&self) -> Option<SocketAddr> {
+        self.control.as_ref().map(|control| control.address)
+    }
+
+    /* waits for the next command */
+    fn receive(&self, control: &Control) -> Option<(u8, Vec<u8>)> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(report) = pending.pop_front() {
+            return Some(report);
+        }
+
+        let reports = control.reports.lock().unwrap().recv().ok()?;
+        self.device.write().unwrap().publish(&mut pending, reports);
+        pending.pop_front()
+    }
 }
 
 impl Device for DummyDevice {
@@ -296,33 +312,118 @@ impl Device for DummyDevice {
     }
 
     fn read(&self) -> Option<(u8, Vec<u8>)> {
+        if let Some(simulation) = &self.simulation {
+            return self.simulate(simulation);
+        }
+        if let Some(control) = &self.control {
+            return self.receive(control);
+        }
+
         /* get all pending */
         let mut pending = self.pending.lock().unwrap();
         if let Some(report) = pending.pop_front() {
-            thread::sleep(Duration::from_secs(2));
             return Some(report);
         }
 
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![80]));
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![70]));
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![60]));
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![50]));
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![60]));
-        pending.push_back((REPORT_ID_REMAININGCAPACITY, vec![70]));
+        let mut cycle = self.cycle.lock().unwrap();
+        if *cycle > 0 {
+            self.clock.sleep(Duration::from_secs(2));
+        }
+        let charge = CHARGE_CYCLE[*cycle % CHARGE_CYCLE.len()];
+        *cycle += 1;
+
+        let report = (REPORT_ID_REMAININGCAPACITY, vec![charge]);
+        self.device.write().unwrap().publish(&mut pending, [report]);
         pending.pop_front()
     }
 }
 
+pub const BACKEND: Backend = Backend {
+    name: "dummy",
+    description: "Simulated UPS cycling its battery charge or running a scenario",
+    schema: ConfigSchema {
+        endpoints: false,
+        options: &[
+            OptionSchema {
+                name: "scenario",
+                kind: OptionKind::Text,
+                required: false,
+                default: None,
+                description: "Name of a shipped scenario or path of a scenario file",
+            },
+            OptionSchema {
+                name: "speed",
+                kind: OptionKind::Number,
+                required: false,
+                default: Some("1"),
+                description: "Scenario speed, 0 runs it without delays",
+            },
+            OptionSchema {
+                name: "control",
+                kind: OptionKind::Number,
+                required: false,
+                default: None,
+                description: "Loopback TCP port accepting commands that change the values, 0 picks a free port",
+            },
+        ],
+    },
+    factory: |config| Ok(Box::new(new_dummy_device(config)?)),
+};
+
 fn struct_to_vec<T: BinarySerde>(data: T) -> Vec<u8> {
     data.binary_serialize_to_array(Endianness::Little)
         .as_slice()
         .into()
 }
 
-pub fn new_dummy_device(_device_config: DeviceConfig) -> DummyDevice {
+pub fn new_dummy_device(device_config: DeviceConfig) -> Result<DummyDevice, DeviceError> {
     info!("Creating Dummy backend");
+
+    let simulation = match device_config.option("scenario") {
+        Some(name) => {
+            let scenario =
+                Scenario::load(name).map_err(|err| DeviceError::InvalidConfig(vec![err]))?;
+            info!("Running scenario {name}");
+            Some(Mutex::new(Simulation::new(scenario)))
+        }
+        None => None,
+    };
+
+    let control = match device_config.option("control") {
+        Some(_) if simulation.is_some() => {
+            return Err(DeviceError::InvalidConfig(vec![
+                ConfigError::ConflictingOptions("control".into(), "scenario".into()),
+            ]));
+        }
+        Some(port) => {
+            let port = port.parse().map_err(|_| {
+                DeviceError::InvalidConfig(vec![ConfigError::InvalidOption {
+                    name: "control".into(),
+                    value: port.into(),
+                }])
+            })?;
+            let control =
+                Control::listen(port).map_err(|err| DeviceError::backend("dummy", err))?;
+            Some(control)
+        }
+        None => None,
+    };
+
+    Ok(DummyDevice {
+        device: RwLock::new(new_dummy_device_data()),
+        pending: Mutex::new(VecDeque::new()),
+        cycle: Mutex::new(0),
+        simulation,
+        speed: device_config.option_parse("speed").unwrap_or(1.0),
+        control,
+        clock: Arc::new(SystemClock),
+    })
+}
+
+/// Device data of the dummy ups, shared with the sim backend
+pub(crate) fn new_dummy_device_data() -> DeviceData {
     let mut device = DeviceData {
-        reports: HashMap::new(),
+        reports: Reports::from_descriptor(UPS_REPORT_DESCRIPTOR),
         strings: HashMap::new(),
         vendor_id: NUT_HID_VID,
         product_id: NUT_HID_PID,
@@ -333,61 +434,84 @@ pub fn new_dummy_device(_device_config: DeviceConfig) -> DummyDevice {
         report_descriptor: UPS_REPORT_DESCRIPTOR.into(),
     };
 
+    device
+        .strings
+        .insert(STRING_ID_DEVICECHEMISTRY, "PbAc".into()); /* Lead acid */
+    device
+        .strings
+        .insert(STRING_ID_OEMVENDOR, NUT_HID_MANUFACTURER.into());
+
     let identification = Identification {
         i_product: STRING_ID_PRODUCT,
         i_serial: STRING_ID_SERIAL,
         i_manufacturer: STRING_ID_MANUFACTURER,
     };
 
+    device.reports.set(
+        ReportType::Feature,
+        REPORT_ID_IDENTIFICAITON,
+        struct_to_vec(identification),
+    );
+
+    let (report_id, status) = PresentStatus::from_bits(&BTreeSet::from([
+        StatusBit::AcPresent,
+        StatusBit::BatteryPresent,
+    ]))
+    .report();
+    device.reports.set_value(report_id, status);
     device
         .reports
-        .insert(REPORT_ID_IDENTIFICAITON, struct_to_vec(identification));
-
-    let status = PresentStatus {
-        ac_present: true,
-        battery_present: true,
-        ..Default::default()
-    };
-
-    device
-        .reports
-        .insert(REPORT_ID_PRESENTSTATUS, struct_to_vec(status));
-    device.reports.insert(REPORT_ID_CAPACITYMODE, [2].into()); /* Percentage */
+        .set(ReportType::Feature, REPORT_ID_CAPACITYMODE, [2]); /* Percentage */
 
     device
         .reports
-        .insert(REPORT_ID_DESIGNCAPACITY, [100].into());
+        .set(ReportType::Feature, REPORT_ID_DESIGNCAPACITY, [100]);
     device
         .reports
-        .insert(REPORT_ID_FULLCHRGECAPACITY, [100].into());
+        .set(ReportType::Feature, REPORT_ID_FULLCHRGECAPACITY, [100]);
+    device.reports.set_value(REPORT_ID_REMAININGCAPACITY, [90]);
     device
         .reports
-        .insert(REPORT_ID_REMAININGCAPACITY, [90].into());
+        .set_value(REPORT_ID_RUNTIMETOEMPTY, runtime_report(121.0 * 60.0).1);
     device
-        .reports
-        .insert(REPORT_ID_RUNTIMETOEMPTY, [121].into()); /* Minutes remaining */
+}
 
-    DummyDevice {
-        device: RwLock::new(device),
-        pending: Mutex::new(VecDeque::new()),
-    }
+/// RunTimeToEmpty input report, in seconds like the unit of the dummy descriptor
+pub(crate) fn runtime_report(seconds: f64) -> (u8, Vec<u8>) {
+    (
+        REPORT_ID_RUNTIMETOEMPTY,
+        (seconds as u16).to_le_bytes().into(),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::FakeClock;
 
     #[test]
     fn present_status() {
-        let status = PresentStatus {
-            discharging: true,
-            shutdown_imminent: true,
-            ..Default::default()
-        };
-
-        let data = struct_to_vec(status);
-
-        assert_eq!(data, [0x02, 0x08]);
+        let (report_id, report) = PresentStatus::from_bits(&BTreeSet::from([
+            StatusBit::Discharging,
+            StatusBit::ShutdownImminent,
+        ]))
+        .report();
+
+        /* the nut backend's status matches the layout of the dummy descriptor */
+        let values = decode::decode(UPS_REPORT_DESCRIPTOR, ReportType::Input, report_id, &report);
+        let set = values
+            .iter()
+            .filter(|value| value.reading == decode::Reading::Flag(true))
+            .map(|value| value.name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            set,
+            [
+                "PresentStatus.Discharging",
+                "PresentStatus.ShutdownImminent"
+            ]
+        );
     }
 
     #[test]
@@ -399,9 +523,200 @@ mod tests {
             ..Default::default()
         };
 
-        let data = struct_to_vec(value);
+        let values = decode::decode(
+            UPS_REPORT_DESCRIPTOR,
+            ReportType::Feature,
+            REPORT_ID_IDENTIFICAITON,
+            &struct_to_vec(value),
+        );
+        let values = values.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            values,
+            ["iProduct = 1", "iSerialNumber = 3", "iManufacturer = 2"]
+        );
+    }
 
-        assert_eq!(data, [0x01, 0x02, 0x03]);
+    #[test]
+    fn read_updates_cached_reports() {
+        let mut device = new_dummy_device(Default::default()).unwrap();
+        let clock = FakeClock::new();
+        device.clock = clock.clone();
+        let start = clock.now();
+        let cached = |device: &DummyDevice| {
+            let data = device.data().read().unwrap();
+            data.reports
+                .get(ReportType::Feature, REPORT_ID_REMAININGCAPACITY)
+                .map(<[u8]>::to_vec)
+        };
+
+        assert_eq!(device.read(), Some((REPORT_ID_REMAININGCAPACITY, vec![80])));
+        assert_eq!(cached(&device), Some(vec![80]));
+        assert_eq!(clock.now(), start);
+
+        /* the next step of the cycle follows 2 seconds later */
+        assert_eq!(device.read(), Some((REPORT_ID_REMAININGCAPACITY, vec![70])));
+        assert_eq!(cached(&device), Some(vec![70]));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn declared_reports_have_values() {
+        let device = new_dummy_device(Default::default()).unwrap();
+        let data = device.data().read().unwrap();
+        for ((report_type, report_id), size) in descriptor::report_sizes(UPS_REPORT_DESCRIPTOR) {
+            assert_eq!(
+                data.reports.get(report_type, report_id).map(<[u8]>::len),
+                Some(size),
+                "{report_type:?} {report_id}"
+            );
+        }
+        assert_eq!(
+            data.reports.get(ReportType::Feature, REPORT_ID_VOLTAGE),
+            Some([0, 0].as_slice())
+        );
+    }
+
+    /// Reports as the log describes them, the published reports are all feature reports too
+    fn describe(reports: &[(u8, Vec<u8>)]) -> Vec<String> {
+        reports
+            .iter()
+            .map(|(report_id, report)| {
+                decode::describe(
+                    UPS_REPORT_DESCRIPTOR,
+                    ReportType::Feature,
+                    *report_id,
+                    report,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_scenario() {
+        let device = new_dummy_device(DeviceConfig::with_options(
+            "dummy",
+            "scenario=short_outage;speed=0",
+        ))
+        .unwrap();
+        let reports = std::iter::from_fn(|| device.read()).collect::<Vec<_>>();
+
+        /* three reports every 5 seconds for 3 minutes */
+        assert_eq!(reports.len(), 3 * 37);
+        let status = reports
+            .iter()
+            .filter(|(report_id, _)| *report_id == REPORT_ID_PRESENTSTATUS)
+            .cloned()
+            .collect::<Vec<_>>();
+        let described = describe(&status);
+        assert_eq!(
+            described[0],
+            "UPS.PowerSummary.PresentStatus.ACPresent = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true, UPS.PowerSummary.PresentStatus.FullyCharged = true"
+        );
+        assert_eq!(
+            described[7],
+            "UPS.PowerSummary.PresentStatus.Discharging = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true"
+        );
+        assert_eq!(
+            described[17],
+            "UPS.PowerSummary.PresentStatus.Discharging = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true"
+        );
+
+        let data = device.data().read().unwrap();
+        assert_eq!(
+            data.reports
+                .get(ReportType::Feature, REPORT_ID_PRESENTSTATUS),
+            Some(status[36].1.as_slice())
+        );
+    }
+
+    #[test]
+    fn accelerated_scenario() {
+        /* 5 second steps at 100 times the speed */
+        let mut device = new_dummy_device(DeviceConfig::with_options(
+            "dummy",
+            "scenario=short_outage;speed=100",
+        ))
+        .unwrap();
+        let clock = FakeClock::new();
+        device.clock = clock.clone();
+        let start = clock.now();
+        for _ in 0..3 * 5 {
+            assert!(device.read().is_some());
+        }
+        /* no wait before the first step */
+        assert_eq!(clock.now() - start, Duration::from_millis(4 * 50));
+    }
+
+    #[test]
+    fn control_commands() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpStream;
+
+        let device = new_dummy_device(DeviceConfig::with_options("dummy", "control=0")).unwrap();
+        let stream = TcpStream::connect(device.control_address().unwrap()).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut command = |line: &str| {
+            writeln!(writer, "{line}").unwrap();
+            let mut answer = String::new();
+            reader.read_line(&mut answer).unwrap();
+            answer.trim_end().to_string()
+        };
+
+        assert_eq!(command("set charge 42"), "ok");
+        assert_eq!(device.read(), Some((REPORT_ID_REMAININGCAPACITY, vec![42])));
+        assert_eq!(
+            device
+                .data()
+                .read()
+                .unwrap()
+                .reports
+                .get(ReportType::Feature, REPORT_ID_REMAININGCAPACITY),
+            Some([42].as_slice())
+        );
+
+        assert_eq!(command("set runtime 5"), "ok");
+        assert_eq!(command("ac off"), "ok");
+        assert_eq!(command("status LB"), "ok");
+        assert_eq!(command("fsd"), "ok");
+        assert_eq!(command("set charge 200"), "error: charge 200 above 100");
+        assert_eq!(command("jump"), "error: unknown command 'jump'");
+
+        let reports = (0..4).map(|_| device.read().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            describe(&reports),
+            [
+                "UPS.PowerSummary.RunTimeToEmpty = 300 s",
+                "UPS.PowerSummary.PresentStatus.Discharging = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true",
+                "UPS.PowerSummary.PresentStatus.Discharging = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true, UPS.PowerSummary.PresentStatus.BelowRemainingCapacityLimit = true",
+                "UPS.PowerSummary.PresentStatus.Discharging = true, UPS.PowerSummary.PresentStatus.BatteryPresent = true, UPS.PowerSummary.PresentStatus.BelowRemainingCapacityLimit = true, UPS.PowerSummary.PresentStatus.ShutdownImminent = true",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_control() {
+        for options in ["control=port", "control=0;scenario=power_loss"] {
+            assert!(
+                matches!(
+                    new_dummy_device(DeviceConfig::with_options("dummy", options)),
+                    Err(DeviceError::InvalidConfig(_))
+                ),
+                "{options}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_scenario() {
+        assert!(matches!(
+            new_dummy_device(DeviceConfig::with_options(
+                "dummy",
+                "scenario=/nonexistent/scenario.toml"
+            )),
+            Err(DeviceError::InvalidConfig(_))
+        ));
     }
 
     #[test]
@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::fmt;
+
+use rups::{ClientError, NutError};
+
+/// A single problem found in a `DeviceConfig`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownBackend(String),
+    NoEndpoints,
+    EmptyHost,
+    InvalidHost(String),
+    InvalidPort(u32),
+    UnknownOption(String),
+    MissingOption(String),
+    InvalidOption { name: String, value: String },
+    InvalidUrl(String),
+    InvalidFile(String),
+    UnknownProfile(String),
+    InvalidDeviceName(String),
+    DuplicateDevice(String),
+    MissingCredential(String),
+    InvalidMapping(String),
+    InvalidScenario(String),
+    ConflictingOptions(String, String),
+    StoredPassword,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnknownBackend(backend) => write!(f, "unknown backend '{backend}'"),
+            ConfigError::NoEndpoints => write!(f, "no host configured"),
+            ConfigError::EmptyHost => write!(f, "empty host"),
+            ConfigError::InvalidHost(host) => write!(f, "invalid host '{host}'"),
+            ConfigError::InvalidPort(port) => write!(f, "invalid port {port}"),
+            ConfigError::UnknownOption(name) => write!(f, "unknown option '{name}'"),
+            ConfigError::MissingOption(name) => write!(f, "missing option '{name}'"),
+            ConfigError::InvalidOption { name, value } => {
+                write!(f, "invalid value '{value}' for option '{name}'")
+            }
+            ConfigError::InvalidUrl(message) => write!(f, "invalid url: {message}"),
+            ConfigError::InvalidFile(message) => write!(f, "invalid configuration file: {message}"),
+            ConfigError::UnknownProfile(name) => write!(f, "unknown profile '{name}'"),
+            ConfigError::InvalidDeviceName(name) => write!(
+                f,
+                "invalid device name '{name}', only letters, digits, '_' and '-' are allowed"
+            ),
+            ConfigError::DuplicateDevice(name) => write!(f, "duplicate device '{name}'"),
+            ConfigError::MissingCredential(source) => {
+                write!(f, "failed to read password from {source}")
+            }
+            ConfigError::InvalidMapping(message) => write!(f, "invalid mapping: {message}"),
+            ConfigError::InvalidScenario(message) => write!(f, "invalid scenario: {message}"),
+            ConfigError::ConflictingOptions(first, second) => {
+                write!(f, "options '{first}' and '{second}' can not be combined")
+            }
+            ConfigError::StoredPassword => write!(
+                f,
+                "the password is not stored with the device, use the 'password_env' or 'password_file' option"
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    InvalidBackend(String),
+    /// All problems found while validating the configuration
+    InvalidConfig(Vec<ConfigError>),
+    /// The backend failed to initialize
+    Backend {
+        backend: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    Nut(ClientError),
+    Authentication(ClientError),
+    /// The report descriptor or device profile is unusable
+    Descriptor(String),
+}
+
+impl DeviceError {
+    pub fn backend(backend: &str, source: impl Into<Box<dyn Error + Send + Sync>>) -> DeviceError {
+        DeviceError::Backend {
+            backend: backend.into(),
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::InvalidBackend(backend) => write!(f, "unknown backend '{backend}'"),
+            DeviceError::InvalidConfig(errors) => {
+                write!(f, "invalid configuration: ")?;
+                for (index, error) in errors.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{error}")?;
+                }
+                Ok(())
+            }
+            DeviceError::Backend { backend, .. } => {
+                write!(f, "failed to initialize {backend} backend")
+            }
+            DeviceError::Nut(_) => write!(f, "NUT protocol error"),
+            DeviceError::Authentication(_) => write!(f, "NUT authentication failed"),
+            DeviceError::Descriptor(message) => write!(f, "invalid descriptor: {message}"),
+        }
+    }
+}
+
+impl Error for DeviceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeviceError::Backend { source, .. } => Some(source.as_ref()),
+            DeviceError::Nut(err) | DeviceError::Authentication(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClientError> for DeviceError {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::Nut(
+                NutError::AccessDenied | NutError::InvalidUsername | NutError::InvalidPassword,
+            ) => DeviceError::Authentication(err),
+            err => DeviceError::Nut(err),
+        }
+    }
+}
+
+/// Formats an error followed by all its sources
+pub struct ErrorChain<'a>(pub &'a dyn Error);
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {err}")?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn invalid_config_lists_all_errors() {
+        let error = DeviceError::InvalidConfig(vec![
+            ConfigError::EmptyHost,
+            ConfigError::InvalidPort(0),
+            ConfigError::UnknownOption("colour".into()),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "invalid configuration: empty host, invalid port 0, unknown option 'colour'"
+        );
+    }
+
+    #[test]
+    fn error_chain() {
+        let error = DeviceError::backend(
+            "replay",
+            io::Error::new(io::ErrorKind::NotFound, "no such file"),
+        );
+        assert_eq!(
+            ErrorChain(&error).to_string(),
+            "failed to initialize replay backend: no such file"
+        );
+    }
+
+    #[test]
+    fn client_error_classification() {
+        let error = DeviceError::from(ClientError::Nut(NutError::AccessDenied));
+        assert!(matches!(error, DeviceError::Authentication(_)));
+        assert!(error.source().is_some());
+
+        let error = DeviceError::from(ClientError::Nut(NutError::DataStale));
+        assert!(matches!(error, DeviceError::Nut(_)));
+    }
+}
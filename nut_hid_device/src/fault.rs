@@ -0,0 +1,510 @@
+//! Wrapper injecting faults into the reports of any backend.
+//!
+//! Faults are enabled by `fault_*` options accepted by every backend. Most of them hit
+//! every n-th report read from the device, counting the reports of the wrapped backend:
+//!
+//! ```text
+//! dummy:?fault_drop=3&fault_truncate=5&fault_lost_after=100
+//! ```
+//!
+//! Only the reports read from the device are changed, the cached feature and input
+//! reports keep the values of the backend. Once the communication is lost the device
+//! reports the lost communication status instead, like the nut backend does for a ups
+//! that stopped answering. This needs a `CommunicationLost` input in the descriptor of
+//! the backend.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use super::*;
+use clock::{Clock, SystemClock};
+use descriptor::Field;
+use log::warn;
+
+/// Interval of the status reports after the communication is lost
+const LOST_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const FAULT_OPTIONS: &[OptionSchema] = &[
+    OptionSchema {
+        name: "fault_delay",
+        kind: OptionKind::Seconds,
+        required: false,
+        default: None,
+        description: "Delay added to every read",
+    },
+    OptionSchema {
+        name: "fault_drop",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Drop every n-th report",
+    },
+    OptionSchema {
+        name: "fault_duplicate",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Send every n-th report twice",
+    },
+    OptionSchema {
+        name: "fault_truncate",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Cut the last byte off every n-th report",
+    },
+    OptionSchema {
+        name: "fault_oversize",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Append a byte to every n-th report",
+    },
+    OptionSchema {
+        name: "fault_out_of_range",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Set the values of every n-th report outside of their logical range",
+    },
+    OptionSchema {
+        name: "fault_lost_after",
+        kind: OptionKind::Integer,
+        required: false,
+        default: None,
+        description: "Report lost communication after n reports",
+    },
+];
+
+/// Faults to inject, every count is the n of "every n-th report"
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Faults {
+    pub delay: Duration,
+    pub drop: Option<u64>,
+    pub duplicate: Option<u64>,
+    pub truncate: Option<u64>,
+    pub oversize: Option<u64>,
+    pub out_of_range: Option<u64>,
+    /// Reports read before the device stops answering
+    pub lost_after: Option<u64>,
+}
+
+impl Faults {
+    pub fn from_config(config: &DeviceConfig) -> Faults {
+        /* every 0th report never comes */
+        let every = |name: &str| config.option_parse::<u64>(name).filter(|every| *every > 0);
+        Faults {
+            delay: config
+                .option("fault_delay")
+                .and_then(parse_seconds)
+                .unwrap_or_default(),
+            drop: every("fault_drop"),
+            duplicate: every("fault_duplicate"),
+            truncate: every("fault_truncate"),
+            oversize: every("fault_oversize"),
+            out_of_range: every("fault_out_of_range"),
+            lost_after: config.option_parse("fault_lost_after"),
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        *self != Faults::default()
+    }
+}
+
+fn hits(every: Option<u64>, count: u64) -> bool {
+    every.is_some_and(|every| count.is_multiple_of(every))
+}
+
+/* little endian bits, the inverse of decoding */
+fn insert(report: &mut [u8], field: &Field, value: u64) {
+    for bit in 0..field.size {
+        let position = (field.offset + bit) as usize;
+        let Some(byte) = report.get_mut(position / 8) else {
+            return;
+        };
+        let mask = 1 << (position % 8);
+        if value >> bit & 1 == 1 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+}
+
+/// A value the field can hold that is outside of its logical range
+fn out_of_range(field: &Field) -> Option<u64> {
+    if field.size == 0 || field.size > 32 {
+        return None;
+    }
+    let (lowest, highest) = match field.logical_minimum < 0 {
+        true => (-(1i64 << (field.size - 1)), (1i64 << (field.size - 1)) - 1),
+        false => (0, (1i64 << field.size) - 1),
+    };
+    let value = if i64::from(field.logical_maximum) < highest {
+        i64::from(field.logical_maximum) + 1
+    } else if i64::from(field.logical_minimum) > lowest {
+        i64::from(field.logical_minimum) - 1
+    } else {
+        return None;
+    };
+    Some(value as u64)
+}
+
+/// Report with only the CommunicationLost flag set, `None` without such a flag
+fn lost_report(descriptor: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let field = decode::find(descriptor, "CommunicationLost")
+        .filter(|field| field.report_type == ReportType::Input)?;
+    let size = descriptor::report_sizes(descriptor)[&(field.report_type, field.report_id)];
+    let mut report = vec![0; size];
+    insert(&mut report, &field, 1);
+    Some((field.report_id, report))
+}
+
+struct FaultState {
+    pending: VecDeque<(u8, Vec<u8>)>,
+    /// Reports read from the wrapped device
+    count: u64,
+    /// The lost communication status was reported
+    lost: bool,
+}
+
+pub struct FaultDevice {
+    device: Box<dyn Device + Send + Sync>,
+    faults: Faults,
+    state: Mutex<FaultState>,
+    /// Status reported once the communication is lost
+    lost_report: Option<(u8, Vec<u8>)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FaultDevice {
+    pub fn new(
+        device: Box<dyn Device + Send + Sync>,
+        faults: Faults,
+    ) -> Result<FaultDevice, DeviceError> {
+        let lost_report = lost_report(&device.data().read().unwrap().report_descriptor);
+        if let Some(lost_after) = faults.lost_after
+            && lost_report.is_none()
+        {
+            /* the backend has no way to tell the host */
+            return Err(DeviceError::InvalidConfig(vec![
+                ConfigError::InvalidOption {
+                    name: "fault_lost_after".into(),
+                    value: lost_after.to_string(),
+                },
+            ]));
+        }
+
+        Ok(FaultDevice {
+            device,
+            faults,
+            state: Mutex::new(FaultState {
+                pending: VecDeque::new(),
+                count: 0,
+                lost: false,
+            }),
+            lost_report,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    fn corrupt(&self, count: u64, report_id: u8, report: &mut Vec<u8>) {
+        if hits(self.faults.out_of_range, count) {
+            let data = self.device.data().read().unwrap();
+            for field in descriptor::fields(&data.report_descriptor) {
+                if field.report_type != ReportType::Input
+                    || field.report_id != report_id
+                    || field.usage.is_none()
+                {
+                    continue;
+                }
+                if let Some(value) = out_of_range(&field) {
+                    insert(report, &field, value);
+                }
+            }
+            warn!("Injecting out of range values into report {report_id}");
+        }
+        if hits(self.faults.truncate, count) {
+            warn!("Injecting truncated report {report_id}");
+            report.pop();
+        }
+        if hits(self.faults.oversize, count) {
+            warn!("Injecting oversized report {report_id}");
+            report.push(0);
+        }
+    }
+
+    /* repeated at a slow pace, the driver keeps reading */
+    fn lose_communication(&self, state: &mut FaultState) -> Option<(u8, Vec<u8>)> {
+        if state.lost {
+            self.clock.sleep(LOST_INTERVAL);
+        } else {
+            warn!("Injecting communication loss");
+            state.lost = true;
+        }
+        let report = self.lost_report.clone()?;
+        let mut data = self.device.data().write().unwrap();
+        data.publish(&mut state.pending, [report]);
+        state.pending.pop_front()
+    }
+}
+
+impl Device for FaultDevice {
+    fn data(&self) -> &RwLock<DeviceData> {
+        self.device.data()
+    }
+
+    fn read(&self) -> Option<(u8, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(report) = state.pending.pop_front() {
+            return Some(report);
+        }
+
+        loop {
+            if self
+                .faults
+                .lost_after
+                .is_some_and(|lost_after| state.count >= lost_after)
+            {
+                return self.lose_communication(&mut state);
+            }
+
+            self.clock.sleep(self.faults.delay);
+            let (report_id, mut report) = self.device.read()?;
+            state.count += 1;
+            let count = state.count;
+
+            if hits(self.faults.drop, count) {
+                warn!("Injecting dropped report {report_id}");
+                continue;
+            }
+
+            self.corrupt(count, report_id, &mut report);
+            if hits(self.faults.duplicate, count) {
+                warn!("Injecting duplicated report {report_id}");
+                state.pending.push_back((report_id, report.clone()));
+            }
+            return Some((report_id, report));
+        }
+    }
+
+    fn set_feature(&self, report_id: u8, report: &[u8]) {
+        self.device.set_feature(report_id, report);
+    }
+
+    fn set_output(&self, report_id: u8, report: &[u8]) {
+        self.device.set_output(report_id, report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::FakeClock;
+    use nut::NutDevice;
+
+    /// Counts its reads, every report holds its number
+    struct CountingDevice {
+        device: RwLock<DeviceData>,
+        count: Mutex<u8>,
+    }
+
+    impl Device for CountingDevice {
+        fn data(&self) -> &RwLock<DeviceData> {
+            &self.device
+        }
+
+        fn read(&self) -> Option<(u8, Vec<u8>)> {
+            let mut count = self.count.lock().unwrap();
+            *count += 1;
+            Some((1, vec![*count, 0]))
+        }
+    }
+
+    #[rustfmt::skip]
+    const DESCRIPTOR: &[u8] = &[
+        0x05, 0x84,       // USAGE_PAGE (Power Device)
+        0x85, 0x01,       // REPORT_ID (1)
+        0x15, 0x00,       // LOGICAL_MINIMUM (0)
+        0x26, 0xE8, 0x03, // LOGICAL_MAXIMUM (1000)
+        0x75, 0x10,       // REPORT_SIZE (16)
+        0x95, 0x01,       // REPORT_COUNT (1)
+        0x09, 0x57,       // USAGE (DelayBeforeShutdown)
+        0x81, 0x02,       // INPUT
+        0x85, 0x02,       // REPORT_ID (2)
+        0x25, 0x01,       // LOGICAL_MAXIMUM (1)
+        0x75, 0x01,       // REPORT_SIZE (1)
+        0x09, 0x73,       // USAGE (CommunicationLost)
+        0x81, 0x02,       // INPUT
+        0x95, 0x07,       // REPORT_COUNT (7)
+        0x81, 0x01,       // INPUT (Constant)
+    ];
+
+    fn wrap(options: &str) -> FaultDevice {
+        let config = DeviceConfig::with_options("dummy", options);
+        let device = CountingDevice {
+            device: RwLock::new(DeviceData {
+                report_descriptor: DESCRIPTOR.into(),
+                ..Default::default()
+            }),
+            count: Mutex::new(0),
+        };
+        FaultDevice::new(Box::new(device), Faults::from_config(&config)).unwrap()
+    }
+
+    fn read(device: &FaultDevice, count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|_| device.read().unwrap().1)
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn whole_counts() {
+        for options in ["fault_drop=2.5", "fault_lost_after=-1"] {
+            let config = DeviceConfig::with_options("dummy", options);
+            assert!(
+                matches!(config.validate(), Err(DeviceError::InvalidConfig(_))),
+                "{options}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_faults() {
+        let config =
+            DeviceConfig::with_options("dummy", "fault_delay=0.5;fault_drop=3;fault_truncate=0");
+        let faults = Faults::from_config(&config);
+        assert_eq!(
+            faults,
+            Faults {
+                delay: Duration::from_millis(500),
+                drop: Some(3),
+                ..Default::default()
+            }
+        );
+        assert!(faults.any());
+        assert!(!Faults::from_config(&DeviceConfig::default()).any());
+    }
+
+    #[test]
+    fn drop_reports() {
+        let device = wrap("fault_drop=3");
+        let numbers = read(&device, 5)
+            .iter()
+            .map(|report| report[0])
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, [1, 2, 4, 5, 7]);
+    }
+
+    #[test]
+    fn duplicate_reports() {
+        let device = wrap("fault_duplicate=2");
+        let numbers = read(&device, 6)
+            .iter()
+            .map(|report| report[0])
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, [1, 2, 2, 3, 4, 4]);
+    }
+
+    #[test]
+    fn report_sizes() {
+        let device = wrap("fault_truncate=2;fault_oversize=3");
+        assert_eq!(
+            read(&device, 5),
+            [vec![1, 0], vec![2], vec![3, 0, 0], vec![4], vec![5, 0]]
+        );
+    }
+
+    #[test]
+    fn communication_loss() {
+        let mut device = wrap("fault_lost_after=2;fault_drop=2");
+        let clock = FakeClock::new();
+        device.clock = clock.clone();
+        let lost = (2, vec![0x01]);
+        assert_eq!(device.read(), Some((1, vec![1, 0])));
+        let start = clock.now();
+        assert_eq!(device.read(), Some(lost.clone()));
+        assert_eq!(clock.now(), start);
+        /* repeated once a second */
+        assert_eq!(device.read(), Some(lost));
+        assert_eq!(clock.now() - start, LOST_INTERVAL);
+    }
+
+    #[test]
+    fn lost_communication_needs_flag() {
+        /* the mini descriptor has no CommunicationLost */
+        let config = DeviceConfig::with_options("mini", "fault_lost_after=1");
+        assert!(matches!(
+            registry().create(config),
+            Err(DeviceError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn delayed_reads() {
+        let mut device = wrap("fault_delay=0.05");
+        let clock = FakeClock::new();
+        device.clock = clock.clone();
+        let start = clock.now();
+        read(&device, 3);
+        assert_eq!(clock.now() - start, Duration::from_millis(150));
+    }
+
+    #[test]
+    fn out_of_range_values() {
+        let device = wrap("fault_out_of_range=2");
+        assert_eq!(read(&device, 3), [vec![1, 0], vec![0xE9, 0x03], vec![3, 0]]);
+
+        let field = descriptor::fields(dummy::UPS_REPORT_DESCRIPTOR)
+            .into_iter()
+            .find(|field| {
+                field.report_type == ReportType::Input
+                    && field.report_id == dummy::REPORT_ID_REMAINTIMELIMIT
+            })
+            .unwrap();
+        assert_eq!(out_of_range(&field), Some(1381));
+
+        /* a bit has no values outside of 0 and 1 */
+        let flag = Field {
+            size: 1,
+            logical_minimum: 0,
+            logical_maximum: 1,
+            ..field.clone()
+        };
+        assert_eq!(out_of_range(&flag), None);
+
+        let signed = Field {
+            size: 16,
+            logical_minimum: -1,
+            logical_maximum: 32767,
+            ..field
+        };
+        assert_eq!(out_of_range(&signed), Some(-2i64 as u64));
+        let mut report = vec![0, 0];
+        insert(&mut report, &signed, -2i64 as u64);
+        assert_eq!(report, [0xFE, 0xFF]);
+    }
+
+    #[test]
+    fn faults_from_registry() {
+        let device = registry()
+            .create(DeviceConfig::with_options("dummy", "fault_lost_after=1"))
+            .unwrap();
+        assert!(device.read().is_some());
+        assert_eq!(device.read(), Some(NutDevice::lost_connection_report()));
+        /* Windows reads the lost communication from the cached status too */
+        let data = device.data().read().unwrap();
+        assert_eq!(
+            decode::lookup(&data, "PresentStatus.CommunicationLost").map(|value| value.reading),
+            Some(decode::Reading::Flag(true))
+        );
+        drop(data);
+        /* the wrapped device keeps its data */
+        assert_eq!(
+            device.data().read().unwrap().report_descriptor,
+            dummy::UPS_REPORT_DESCRIPTOR
+        );
+    }
+}
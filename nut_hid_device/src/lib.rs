@@ -1,15 +1,86 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::RwLock,
+    time::Duration,
+};
 
-use crate::{dummy::DummyDevice, mini::MiniDevice, nut::NutDevice};
+pub use error::{ConfigError, DeviceError, ErrorChain};
+use log::{debug, warn};
+pub use registry::{Backend, ConfigSchema, OptionKind, OptionSchema, Registry, registry};
+
+pub mod check;
+pub mod clock;
+pub mod config_file;
 pub mod constants;
+pub mod control;
+pub mod decode;
+pub mod descriptor;
 pub mod dummy;
+pub mod error;
+pub mod fault;
+pub mod mapping;
 pub mod mini;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod nut;
+pub mod nutdump;
+pub mod registry;
+pub mod replay;
+pub mod scenario;
+pub mod sim;
+pub mod status;
+pub mod url;
+
+/// Kind of report, a report id can be used by more than one kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+/// Current report values keyed by type and report id, descriptors without report ids
+/// use id 0
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Reports {
+    reports: HashMap<(ReportType, u8), Vec<u8>>,
+}
+
+impl Reports {
+    /// Zeroed reports of the size the descriptor declares, for every report in it
+    pub fn from_descriptor(descriptor: &[u8]) -> Reports {
+        Reports {
+            reports: descriptor::report_sizes(descriptor)
+                .into_iter()
+                .map(|(key, size)| (key, vec![0; size]))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, report_type: ReportType, report_id: u8) -> Option<&[u8]> {
+        self.reports
+            .get(&(report_type, report_id))
+            .map(Vec::as_slice)
+    }
+
+    pub fn set(&mut self, report_type: ReportType, report_id: u8, report: impl Into<Vec<u8>>) {
+        self.reports.insert((report_type, report_id), report.into());
+    }
+
+    /// Value of the ups, returned both as input and as feature report
+    pub fn set_value(&mut self, report_id: u8, report: impl Into<Vec<u8>>) {
+        let report = report.into();
+        self.set(ReportType::Input, report_id, report.clone());
+        self.set(ReportType::Feature, report_id, report);
+    }
+}
 
 #[derive(Default)]
 
 pub struct DeviceData {
-    pub reports: HashMap<u8, Vec<u8>>,
+    pub reports: Reports,
     pub strings: HashMap<u8, String>,
     pub vendor_id: u16,
     pub product_id: u16,
@@ -20,53 +91,449 @@ pub struct DeviceData {
     pub report_descriptor: Vec<u8>,
 }
 
-#[derive(Default, Debug)]
-pub struct DeviceConfig {
+impl DeviceData {
+    /// Queue input reports for READ_REPORT and update the values GET_FEATURE and
+    /// GET_INPUT_REPORT return in the same step, so the requests never disagree
+    pub fn publish(
+        &mut self,
+        pending: &mut VecDeque<(u8, Vec<u8>)>,
+        reports: impl IntoIterator<Item = (u8, Vec<u8>)>,
+    ) {
+        for (report_id, report) in reports {
+            debug!(
+                "Publishing {}",
+                decode::describe(
+                    &self.report_descriptor,
+                    ReportType::Input,
+                    report_id,
+                    &report
+                )
+            );
+            self.reports.set_value(report_id, report.clone());
+            pending.push_back((report_id, report));
+        }
+    }
+
+    /// String of an index used in the descriptor, the identity strings unless the
+    /// backend has set its own
+    pub fn indexed_string(&self, index: u8) -> Option<&str> {
+        if let Some(string) = self.strings.get(&index) {
+            return Some(string);
+        }
+        match index {
+            constants::STRING_ID_MANUFACTURER => Some(&self.manufacturer),
+            constants::STRING_ID_PRODUCT => Some(&self.product),
+            constants::STRING_ID_SERIAL => Some(&self.serial_number),
+            _ => None,
+        }
+    }
+
+    /// Whether the descriptor declares report ids, without them every report has id 0
+    pub fn uses_report_ids(&self) -> bool {
+        descriptor::uses_report_ids(&self.report_descriptor)
+    }
+}
+
+/// Default port of a NUT server
+pub const NUT_DEFAULT_PORT: u32 = 3493;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
     pub host: String,
     pub port: u32,
-    pub backend: String,
 }
 
-pub trait Device {
-    fn data(&self) -> &RwLock<DeviceData>;
-    fn read(&self) -> Option<(u8, Vec<u8>)>;
+impl Endpoint {
+    pub fn new(host: &str, port: u32) -> Endpoint {
+        Endpoint {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Parse a single `host`, `host:port`, `[v6]` or `[v6]:port` entry
+    pub fn parse(value: &str, default_port: u32) -> Option<Endpoint> {
+        let value = value.trim();
+        if let Some(rest) = value.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']')?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => port.parse().ok()?,
+                None if rest.is_empty() => default_port,
+                None => return None,
+            };
+            return Some(Endpoint::new(host, port));
+        }
+
+        match value.split_once(':') {
+            /* more than one colon is a bare IPv6 address */
+            Some((host, port)) if !port.contains(':') => {
+                Some(Endpoint::new(host, port.parse().ok()?))
+            }
+            _ => Some(Endpoint::new(value, default_port)),
+        }
+    }
+
+    /// Parse a comma separated list of endpoints, in priority order
+    pub fn parse_list(value: &str, default_port: u32) -> Result<Vec<Endpoint>, ConfigError> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                Endpoint::parse(entry, default_port)
+                    .ok_or_else(|| ConfigError::InvalidHost(entry.trim().into()))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
 }
 
-pub enum DeviceEnum {
-    NutDevice(NutDevice),
-    DummyDevice(DummyDevice),
-    MiniDevice(MiniDevice),
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    /// Servers to use, the first one is the primary
+    pub endpoints: Vec<Endpoint>,
+    pub backend: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Ups to use, the first one listed by the server if not set
+    pub ups: Option<String>,
+    /// Backend specific options
+    pub options: BTreeMap<String, String>,
+}
+
+/* keep passwords out of logs */
+impl fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("endpoints", &self.endpoints)
+            .field("backend", &self.backend)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("ups", &self.ups)
+            .field("options", &self.options)
+            .finish()
+    }
 }
 
-impl Device for DeviceEnum {
-    fn data(&self) -> &RwLock<DeviceData> {
-        match self {
-            DeviceEnum::NutDevice(device) => device.data(),
-            DeviceEnum::DummyDevice(device) => device.data(),
-            DeviceEnum::MiniDevice(device) => device.data(),
+impl DeviceConfig {
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    /// Get an option converted to a type, invalid values are ignored
+    pub fn option_parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        let value = self.option(name)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("Ignoring invalid value for option {name}: {value}");
+                None
+            }
         }
     }
 
-    fn read(&self) -> Option<(u8, Vec<u8>)> {
-        match self {
-            DeviceEnum::NutDevice(device) => device.read(),
-            DeviceEnum::DummyDevice(device) => device.read(),
-            DeviceEnum::MiniDevice(device) => device.read(),
+    /// The password, or the one the `password_env` or `password_file` option refers to
+    pub fn resolve_password(&self) -> Result<Option<String>, ConfigError> {
+        if let Some(name) = self.option("password_env") {
+            return std::env::var(name).map(Some).map_err(|_| {
+                ConfigError::MissingCredential(format!("environment variable {name}"))
+            });
         }
+        if let Some(path) = self.option("password_file") {
+            return std::fs::read_to_string(path)
+                .map(|password| Some(password.trim_end_matches(['\r', '\n']).into()))
+                .map_err(|err| ConfigError::MissingCredential(format!("{path}: {err}")));
+        }
+        Ok(self.password.clone())
+    }
+
+    /// Check the configuration against the schema of its backend
+    pub fn validate(&self) -> Result<(), DeviceError> {
+        registry().validate(self)
+    }
+
+    pub fn check_endpoints(&self, errors: &mut Vec<ConfigError>) {
+        if self.endpoints.is_empty() {
+            errors.push(ConfigError::NoEndpoints);
+        }
+        for endpoint in &self.endpoints {
+            if endpoint.host.is_empty() {
+                errors.push(ConfigError::EmptyHost);
+            }
+            if endpoint.port == 0 || endpoint.port > u16::MAX.into() {
+                errors.push(ConfigError::InvalidPort(endpoint.port));
+            }
+        }
+    }
+
+    pub fn check_options(&self, known: &[&str], errors: &mut Vec<ConfigError>) {
+        for name in self.options.keys() {
+            if !known.contains(&name.as_str()) {
+                errors.push(ConfigError::UnknownOption(name.clone()));
+            }
+        }
+    }
+
+    pub fn check_option(
+        &self,
+        name: &str,
+        valid: impl Fn(&str) -> bool,
+        errors: &mut Vec<ConfigError>,
+    ) {
+        if let Some(value) = self.option(name)
+            && !valid(value)
+        {
+            errors.push(ConfigError::InvalidOption {
+                name: name.into(),
+                value: value.into(),
+            });
+        }
+    }
+
+    pub fn require_option(&self, name: &str, errors: &mut Vec<ConfigError>) {
+        if self.option(name).is_none() {
+            errors.push(ConfigError::MissingOption(name.into()));
+        }
+    }
+
+    /// Parse a `key=value;key=value` list of options
+    pub fn parse_options(value: &str) -> BTreeMap<String, String> {
+        value
+            .split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| match entry.split_once('=') {
+                Some((key, value)) => (key.trim().into(), value.trim().into()),
+                None => (entry.trim().into(), "".into()),
+            })
+            .collect()
     }
-}
 
-impl DeviceEnum {
-    pub fn from_config(config: DeviceConfig) -> Result<DeviceEnum, DeviceError> {
-        match config.backend.as_str() {
-            "nut" => Ok(Self::NutDevice(nut::new_nut_device(config))),
-            "dummy" => Ok(Self::DummyDevice(dummy::new_dummy_device(config))),
-            "mini" => Ok(Self::MiniDevice(mini::new_mini_device())),
-            _ => Err(DeviceError::InvalidBackend),
+    /// Configuration of a backend without endpoints, the options as `parse_options` takes them
+    pub fn with_options(backend: &str, options: &str) -> DeviceConfig {
+        DeviceConfig {
+            backend: backend.into(),
+            options: DeviceConfig::parse_options(options),
+            ..Default::default()
         }
     }
 }
 
-pub enum DeviceError {
-    InvalidBackend,
+pub trait Device {
+    fn data(&self) -> &RwLock<DeviceData>;
+    fn read(&self) -> Option<(u8, Vec<u8>)>;
+
+    /// Feature report written by the host
+    fn set_feature(&self, report_id: u8, report: &[u8]) {
+        let reports = &mut self.data().write().unwrap().reports;
+        reports.set(ReportType::Feature, report_id, report);
+    }
+
+    /// Output report written by the host
+    fn set_output(&self, report_id: u8, report: &[u8]) {
+        let reports = &mut self.data().write().unwrap().reports;
+        reports.set(ReportType::Output, report_id, report);
+    }
+}
+
+/// Create a device using one of the built-in backends
+pub fn from_config(config: DeviceConfig) -> Result<Box<dyn Device + Send + Sync>, DeviceError> {
+    registry().create(config)
+}
+
+/// Parse a boolean option value
+pub fn parse_flag(value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a non negative number of seconds
+pub fn parse_seconds(value: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(value.parse().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_by_type() {
+        let mut reports = Reports::default();
+        reports.set_value(7, [1]);
+        reports.set(ReportType::Output, 7, [2]);
+        assert_eq!(reports.get(ReportType::Input, 7), Some([1].as_slice()));
+        assert_eq!(reports.get(ReportType::Feature, 7), Some([1].as_slice()));
+        assert_eq!(reports.get(ReportType::Output, 7), Some([2].as_slice()));
+
+        /* descriptors without report ids use id 0 */
+        reports.set(ReportType::Feature, 0, [3, 4]);
+        assert_eq!(reports.get(ReportType::Feature, 0), Some([3, 4].as_slice()));
+        assert_eq!(reports.get(ReportType::Input, 0), None);
+    }
+
+    #[test]
+    fn endpoint_parse() {
+        assert_eq!(
+            Endpoint::parse("localhost", 3493),
+            Some(Endpoint::new("localhost", 3493))
+        );
+        assert_eq!(
+            Endpoint::parse("ups1:1234", 3493),
+            Some(Endpoint::new("ups1", 1234))
+        );
+        assert_eq!(
+            Endpoint::parse("::1", 3493),
+            Some(Endpoint::new("::1", 3493))
+        );
+        assert_eq!(
+            Endpoint::parse("[fe80::1]:1234", 3493),
+            Some(Endpoint::new("fe80::1", 1234))
+        );
+        assert_eq!(Endpoint::parse("ups1:port", 3493), None);
+        assert_eq!(Endpoint::parse("[::1", 3493), None);
+    }
+
+    #[test]
+    fn endpoint_parse_list() {
+        assert_eq!(
+            Endpoint::parse_list("ups1, ups2:1234,[::1]:5,", 3493),
+            Ok(vec![
+                Endpoint::new("ups1", 3493),
+                Endpoint::new("ups2", 1234),
+                Endpoint::new("::1", 5),
+            ])
+        );
+        assert_eq!(Endpoint::parse_list("", 3493), Ok(vec![]));
+        assert_eq!(
+            Endpoint::parse_list("ups1, ups2:port", 3493),
+            Err(ConfigError::InvalidHost("ups2:port".into()))
+        );
+    }
+
+    #[test]
+    fn resolve_password() {
+        let mut config = DeviceConfig {
+            password: Some("secret".into()),
+            ..Default::default()
+        };
+        assert_eq!(config.resolve_password(), Ok(Some("secret".into())));
+
+        config.options = DeviceConfig::parse_options("password_env=NUT_HID_TEST_UNSET_VARIABLE");
+        assert!(matches!(
+            config.resolve_password(),
+            Err(ConfigError::MissingCredential(_))
+        ));
+        config.options = DeviceConfig::parse_options("password_file=/nonexistent/password");
+        assert!(matches!(
+            config.resolve_password(),
+            Err(ConfigError::MissingCredential(_))
+        ));
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = DeviceConfig::parse_options("file=C:\\ups.rec; speed=10;;flag");
+        assert_eq!(options["file"], "C:\\ups.rec");
+        assert_eq!(options["speed"], "10");
+        assert_eq!(options["flag"], "");
+        assert_eq!(options.len(), 3);
+    }
+
+    #[test]
+    fn option_parse() {
+        let config = DeviceConfig::with_options("dummy", "speed=10;poll=soon");
+        assert_eq!(config.option_parse::<f64>("speed"), Some(10.0));
+        assert_eq!(config.option_parse::<f64>("poll"), None);
+        assert_eq!(config.option_parse::<f64>("missing"), None);
+    }
+
+    #[test]
+    fn validate() {
+        let config = DeviceConfig {
+            endpoints: vec![Endpoint::new("ups1", 3493)],
+            ..DeviceConfig::with_options("nut", "poll=5")
+        };
+        assert!(config.validate().is_ok());
+
+        let config = DeviceConfig {
+            backend: "dummy".into(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_reports_all_errors() {
+        let config = DeviceConfig {
+            endpoints: vec![Endpoint::new("", 3493), Endpoint::new("ups2", 0)],
+            ..DeviceConfig::with_options("nut", "poll=-1;colour=red")
+        };
+        let Err(DeviceError::InvalidConfig(errors)) = config.validate() else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::EmptyHost,
+                ConfigError::InvalidPort(0),
+                ConfigError::UnknownOption("colour".into()),
+                ConfigError::InvalidOption {
+                    name: "poll".into(),
+                    value: "-1".into()
+                },
+            ]
+        );
+
+        let config = DeviceConfig {
+            backend: "nut".into(),
+            ..Default::default()
+        };
+        let Err(DeviceError::InvalidConfig(errors)) = config.validate() else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(errors, vec![ConfigError::NoEndpoints]);
+
+        let config = DeviceConfig {
+            backend: "serial".into(),
+            ..Default::default()
+        };
+        let Err(DeviceError::InvalidConfig(errors)) = config.validate() else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(errors, vec![ConfigError::UnknownBackend("serial".into())]);
+    }
+
+    #[test]
+    fn from_config_validates() {
+        let config = DeviceConfig::with_options("dummy", "file=ups.rec");
+        assert!(matches!(
+            from_config(config),
+            Err(DeviceError::InvalidConfig(_))
+        ));
+
+        let config = DeviceConfig {
+            backend: "serial".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            from_config(config),
+            Err(DeviceError::InvalidBackend(_))
+        ));
+    }
+
+    #[test]
+    fn endpoint_display() {
+        assert_eq!(Endpoint::new("ups1", 3493).to_string(), "ups1:3493");
+        assert_eq!(Endpoint::new("::1", 3493).to_string(), "[::1]:3493");
+    }
 }
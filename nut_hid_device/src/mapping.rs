@@ -0,0 +1,537 @@
+//! Mapping of NUT variables to HID usages.
+//!
+//! Each usage is computed from a chain of sources, the first variable the ups
+//! provides wins. The PresentStatus bits are computed by the rules of `status`. A
+//! mapping file replaces the built-in entries for the usages and bits it lists. Usages
+//! and bits are also accepted as usbhid-ups style paths such as
+//! `UPS.PowerSummary.PresentStatus.ACPresent`:
+//!
+//! ```toml
+//! [[mapping]]
+//! usage = "RemainingCapacity"
+//! variable = "battery.charge"
+//! fallback = [{ variable = "battery.voltage", scale = 50.0, offset = -600.0 }]
+//! min = 0
+//! max = 100
+//!
+//! [status]
+//! battery_present = "true"
+//! below_remaining_capacity_limit = "LB | battery.charge < 10"
+//! ```
+
+use std::fs;
+
+use serde::Deserialize;
+
+use super::*;
+use descriptor::usage;
+use log::debug;
+use nut::Variables;
+use rups::ClientError;
+use status::{StatusBit, StatusRules};
+
+/// HID usage a mapping produces, each one is an input report
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Usage {
+    /// Percent of full charge
+    RemainingCapacity,
+    /// Percent below which the battery is considered low
+    RemainingCapacityLimit,
+    /// Minutes until the battery is empty
+    RunTimeToEmpty,
+    /// Seconds until the ups shuts down, -1 if no shutdown is pending
+    DelayBeforeShutdown,
+}
+
+impl Usage {
+    const ALL: [Usage; 4] = [
+        Usage::RemainingCapacity,
+        Usage::RemainingCapacityLimit,
+        Usage::RunTimeToEmpty,
+        Usage::DelayBeforeShutdown,
+    ];
+
+    pub fn usage(&self) -> u32 {
+        match self {
+            Usage::RemainingCapacity => usage(0x85, 0x66),
+            Usage::RemainingCapacityLimit => usage(0x85, 0x29),
+            Usage::RunTimeToEmpty => usage(0x85, 0x68),
+            Usage::DelayBeforeShutdown => usage(0x84, 0x57),
+        }
+    }
+
+    /// Usage by its name or its path in the UPS descriptor, e.g.
+    /// `UPS.PowerSummary.RunTimeToEmpty`
+    pub fn from_name(name: &str) -> Option<Usage> {
+        let field = decode::find(nut::UPS_REPORT_DESCRIPTOR, name)?;
+        Usage::ALL
+            .into_iter()
+            .find(|usage| Some(usage.usage()) == field.usage)
+    }
+
+    /// Report data of a value, truncated and saturated to the report range
+    pub fn encode(&self, value: f64) -> Vec<u8> {
+        let value = truncate(value);
+        match self {
+            Usage::RemainingCapacity | Usage::RemainingCapacityLimit => vec![value as u8],
+            Usage::RunTimeToEmpty => (value as u16).to_le_bytes().into(),
+            Usage::DelayBeforeShutdown => (value as i16).to_le_bytes().into(),
+        }
+    }
+}
+
+/* whole minutes of runtime like NUT reports them, without losing a minute to a scale
+that lands just below a whole number */
+fn truncate(value: f64) -> f64 {
+    let nearest = value.round();
+    match (value - nearest).abs() < 1e-9 {
+        true => nearest,
+        false => value.trunc(),
+    }
+}
+
+impl TryFrom<String> for Usage {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Usage, String> {
+        Usage::from_name(&name).ok_or_else(|| format!("unknown usage '{name}'"))
+    }
+}
+
+/// A variable and the linear conversion applied to it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub variable: String,
+    #[serde(default = "one")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+impl Source {
+    pub fn new(variable: &str) -> Source {
+        Source {
+            variable: variable.into(),
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+
+    pub fn scaled(variable: &str, scale: f64, offset: f64) -> Source {
+        Source {
+            variable: variable.into(),
+            scale,
+            offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub usage: Usage,
+    /// Sources in priority order
+    pub sources: Vec<Source>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Mapping {
+    pub fn new(usage: Usage, sources: Vec<Source>) -> Mapping {
+        Mapping {
+            usage,
+            sources,
+            min: None,
+            max: None,
+        }
+    }
+
+    /// Value of the usage, `None` if no source is available
+    pub fn evaluate(&self, variables: &mut dyn Variables) -> Result<Option<Vec<u8>>, ClientError> {
+        for source in &self.sources {
+            let Some(value) = variables.get_str(&source.variable)? else {
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<f64>() else {
+                debug!("Ignoring non numeric {} '{value}'", source.variable);
+                continue;
+            };
+
+            let mut value = value * source.scale + source.offset;
+            if let Some(min) = self.min {
+                value = value.max(min);
+            }
+            if let Some(max) = self.max {
+                value = value.min(max);
+            }
+            return Ok(Some(self.usage.encode(value)));
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingSection {
+    usage: Usage,
+    variable: String,
+    #[serde(default = "one")]
+    scale: f64,
+    #[serde(default)]
+    offset: f64,
+    #[serde(default)]
+    fallback: Vec<Source>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingFile {
+    #[serde(default)]
+    mapping: Vec<MappingSection>,
+    #[serde(default)]
+    status: BTreeMap<String, String>,
+}
+
+/// Mappings evaluated on every poll, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappingTable {
+    pub mappings: Vec<Mapping>,
+    pub status: StatusRules,
+}
+
+impl Default for MappingTable {
+    fn default() -> MappingTable {
+        MappingTable {
+            mappings: vec![
+                Mapping::new(
+                    Usage::RemainingCapacity,
+                    vec![Source::new("battery.charge")],
+                ),
+                Mapping::new(
+                    Usage::RemainingCapacityLimit,
+                    vec![Source::new("battery.charge.low")],
+                ),
+                Mapping::new(
+                    Usage::RunTimeToEmpty,
+                    vec![Source::scaled("battery.runtime", 1.0 / 60.0, 0.0)],
+                ),
+                Mapping::new(
+                    Usage::DelayBeforeShutdown,
+                    vec![Source::new("ups.timer.shutdown")],
+                ),
+            ],
+            status: StatusRules::default(),
+        }
+    }
+}
+
+impl MappingTable {
+    /// Add a mapping, replacing the one for the same usage
+    pub fn set(&mut self, mapping: Mapping) {
+        match self
+            .mappings
+            .iter_mut()
+            .find(|existing| existing.usage == mapping.usage)
+        {
+            Some(existing) => *existing = mapping,
+            None => self.mappings.push(mapping),
+        }
+    }
+
+    /// The built-in table with the mappings of a mapping file applied
+    pub fn parse(text: &str) -> Result<MappingTable, ConfigError> {
+        let file: MappingFile =
+            toml::from_str(text).map_err(|err| ConfigError::InvalidMapping(err.to_string()))?;
+
+        let mut table = MappingTable::default();
+        for section in file.mapping {
+            if let (Some(min), Some(max)) = (section.min, section.max)
+                && min > max
+            {
+                return Err(ConfigError::InvalidMapping(format!(
+                    "{:?}: min {min} above max {max}",
+                    section.usage
+                )));
+            }
+
+            let mut sources = vec![Source::scaled(
+                &section.variable,
+                section.scale,
+                section.offset,
+            )];
+            sources.extend(section.fallback);
+            table.set(Mapping {
+                usage: section.usage,
+                sources,
+                min: section.min,
+                max: section.max,
+            });
+        }
+        let mut rules = BTreeMap::new();
+        for (name, rule) in file.status {
+            let bit = StatusBit::from_name(&name).ok_or_else(|| {
+                ConfigError::InvalidMapping(format!("unknown status bit '{name}'"))
+            })?;
+            rules.insert(bit, rule);
+        }
+        table.status.set(rules)?;
+        Ok(table)
+    }
+
+    pub fn load(path: &str) -> Result<MappingTable, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::InvalidMapping(format!("{path}: {err}")))?;
+        MappingTable::parse(&text)
+    }
+
+    /// Table selected by the `mapping` option, the built-in one if not set
+    pub fn from_config(config: &DeviceConfig) -> Result<MappingTable, DeviceError> {
+        match config.option("mapping") {
+            Some(path) => {
+                MappingTable::load(path).map_err(|err| DeviceError::InvalidConfig(vec![err]))
+            }
+            None => Ok(MappingTable::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use replay::Snapshot;
+
+    fn snapshot(variables: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            variables: variables
+                .iter()
+                .map(|(name, value)| (name.to_string(), Some(value.to_string())))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut mapping = Mapping::new(
+            Usage::RemainingCapacity,
+            vec![
+                Source::new("battery.charge"),
+                Source::scaled("battery.voltage", 50.0, -600.0),
+            ],
+        );
+        mapping.min = Some(0.0);
+        mapping.max = Some(100.0);
+
+        let mut variables = snapshot(&[("battery.charge", "80.4"), ("battery.voltage", "13")]);
+        assert_eq!(mapping.evaluate(&mut variables).unwrap(), Some(vec![80]));
+
+        let mut variables = snapshot(&[("battery.charge", "n/a"), ("battery.voltage", "12.5")]);
+        assert_eq!(mapping.evaluate(&mut variables).unwrap(), Some(vec![25]));
+
+        let mut variables = snapshot(&[("battery.voltage", "14.1")]);
+        assert_eq!(mapping.evaluate(&mut variables).unwrap(), Some(vec![100]));
+
+        let mut variables = snapshot(&[("battery.voltage", "10")]);
+        assert_eq!(mapping.evaluate(&mut variables).unwrap(), Some(vec![0]));
+
+        assert_eq!(mapping.evaluate(&mut snapshot(&[])).unwrap(), None);
+    }
+
+    #[test]
+    fn evaluate_saturates() {
+        let mapping = Mapping::new(Usage::RunTimeToEmpty, vec![Source::new("battery.runtime")]);
+        let mut variables = snapshot(&[("battery.runtime", "100000")]);
+        assert_eq!(
+            mapping.evaluate(&mut variables).unwrap(),
+            Some(vec![255, 255])
+        );
+        let mut variables = snapshot(&[("battery.runtime", "-5")]);
+        assert_eq!(mapping.evaluate(&mut variables).unwrap(), Some(vec![0, 0]));
+    }
+
+    #[test]
+    fn runtime_in_whole_minutes() {
+        let table = MappingTable::default();
+        let mapping = table
+            .mappings
+            .iter()
+            .find(|mapping| mapping.usage == Usage::RunTimeToEmpty)
+            .unwrap();
+        for (seconds, minutes) in [("59", 0u16), ("90", 1), ("119.9", 1), ("120", 2)] {
+            let mut variables = snapshot(&[("battery.runtime", seconds)]);
+            assert_eq!(
+                mapping.evaluate(&mut variables).unwrap(),
+                Some(minutes.to_le_bytes().to_vec()),
+                "{seconds}"
+            );
+        }
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(Usage::RemainingCapacity.encode(80.5), [80]);
+        assert_eq!(Usage::RemainingCapacity.encode(0.29 * 100.0), [29]);
+        assert_eq!(Usage::RunTimeToEmpty.encode(300.0), [0x2c, 0x01]);
+        assert_eq!(Usage::DelayBeforeShutdown.encode(300.0), [0x2c, 0x01]);
+        assert_eq!(Usage::DelayBeforeShutdown.encode(-1.0), [0xff, 0xff]);
+        assert_eq!(Usage::DelayBeforeShutdown.encode(100000.0), [0xff, 0x7f]);
+    }
+
+    #[test]
+    fn parse() {
+        let table = MappingTable::parse(
+            r#"
+            [[mapping]]
+            usage = "RunTimeToEmpty"
+            variable = "experimental.runtime.minutes"
+            fallback = [{ variable = "battery.runtime", scale = 0.0166667 }]
+            max = 120
+
+            [[mapping]]
+            usage = "RemainingCapacityLimit"
+            variable = "battery.charge.warning"
+            "#,
+        )
+        .unwrap();
+
+        let defaults = MappingTable::default();
+        assert_eq!(table.mappings.len(), 4);
+        assert_eq!(table.mappings[0], defaults.mappings[0]);
+        assert_eq!(
+            table.mappings[1],
+            Mapping::new(
+                Usage::RemainingCapacityLimit,
+                vec![Source::new("battery.charge.warning")]
+            )
+        );
+        assert_eq!(
+            table.mappings[2],
+            Mapping {
+                usage: Usage::RunTimeToEmpty,
+                sources: vec![
+                    Source::new("experimental.runtime.minutes"),
+                    Source::scaled("battery.runtime", 0.0166667, 0.0),
+                ],
+                min: None,
+                max: Some(120.0),
+            }
+        );
+
+        assert_eq!(MappingTable::parse("").unwrap(), defaults);
+    }
+
+    #[test]
+    fn parse_paths() {
+        let table = MappingTable::parse(
+            r#"
+            [[mapping]]
+            usage = "UPS.PowerSummary.RemainingCapacity"
+            variable = "battery.charge.approx"
+
+            [[mapping]]
+            usage = "PowerSummary.DelayBeforeShutdown"
+            variable = "ups.delay.shutdown"
+
+            [status]
+            "UPS.PowerSummary.PresentStatus.ACPresent" = "OL"
+            "PresentStatus.Overload" = "OVER"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(table.mappings.len(), 4);
+        assert_eq!(
+            table.mappings[0],
+            Mapping::new(
+                Usage::RemainingCapacity,
+                vec![Source::new("battery.charge.approx")]
+            )
+        );
+        assert_eq!(
+            table.mappings[3],
+            Mapping::new(
+                Usage::DelayBeforeShutdown,
+                vec![Source::new("ups.delay.shutdown")]
+            )
+        );
+        assert!(table.status.rules.contains_key(&StatusBit::AcPresent));
+        assert!(table.status.rules.contains_key(&StatusBit::Overload));
+
+        for text in [
+            "[[mapping]]\nusage = \"UPS.PowerSummary.FullChargeCapacity\"\nvariable = \"a\"\n",
+            "[status]\n\"UPS.PowerSummary.RemainingCapacity\" = \"OL\"\n",
+        ] {
+            assert!(
+                matches!(
+                    MappingTable::parse(text),
+                    Err(ConfigError::InvalidMapping(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_status() {
+        let table = MappingTable::parse(
+            "[status]\nbattery_present = \"true\"\nshutdown_imminent = \"LB & OB\"\n",
+        )
+        .unwrap();
+        assert_eq!(table.mappings, MappingTable::default().mappings);
+        assert_eq!(
+            table.status.rules[&StatusBit::BatteryPresent],
+            status::Expr::Const(true)
+        );
+        assert!(
+            table
+                .status
+                .rules
+                .contains_key(&StatusBit::ShutdownImminent)
+        );
+        assert!(table.status.rules.contains_key(&StatusBit::AcPresent));
+
+        for text in [
+            "[status]\nplugged_in = \"OL\"\n",
+            "[status]\nac_present = \"OL |\"\n",
+        ] {
+            assert!(
+                matches!(
+                    MappingTable::parse(text),
+                    Err(ConfigError::InvalidMapping(_))
+                ),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in [
+            "[[mapping]]\nusage = \"Voltage\"\nvariable = \"input.voltage\"\n",
+            "[[mapping]]\nusage = \"RemainingCapacity\"\n",
+            "[[mapping]]\nusage = \"RemainingCapacity\"\nvariable = \"a\"\nfactor = 2\n",
+            "[[mapping]]\nusage = \"RemainingCapacity\"\nvariable = \"a\"\nmin = 10\nmax = 5\n",
+        ] {
+            assert!(
+                matches!(
+                    MappingTable::parse(text),
+                    Err(ConfigError::InvalidMapping(_))
+                ),
+                "{text}"
+            );
+        }
+        assert!(matches!(
+            MappingTable::from_config(
+                &DeviceConfig::parse_url("nut:?mapping=%2Fnonexistent").unwrap()
+            ),
+            Err(DeviceError::InvalidConfig(_))
+        ));
+    }
+}
@@ -1,8 +1,21 @@
-use std::collections::HashMap;
+//! Loopback device modelled on the hidmini sample of the Windows driver samples.
+//!
+//! Output reports are echoed back as input reports and feature reports round-trip. A
+//! feature report holding a `HidMiniControlInfo` with one of the `HIDMINI_CONTROL_CODE_*`
+//! codes changes how the device answers, so the request plumbing of the driver can be
+//! tested against a device that behaves the same on every run.
+//!
+//! A read delay of 0 is only meant for tests: every read then answers at once with
+//! the current input report, and the driver reads again without pause.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
 use super::*;
 use constants::*;
-use log::info;
+use log::{debug, info, warn};
 
 #[repr(C, packed(1))]
 #[derive(Debug, Copy, Clone)]
@@ -11,8 +24,9 @@ pub struct HidMiniControlInfo {
     ReportId: ::core::ffi::c_uchar,
     ControlCode: ::core::ffi::c_uchar,
 
-    Dummy1: ::core::ffi::c_ulong,
-    Dummy2: ::core::ffi::c_ulong,
+    /* ULONG, which is 32 bits on every Windows target */
+    Dummy1: u32,
+    Dummy2: u32,
 }
 
 #[repr(C, packed(1))]
@@ -30,13 +44,54 @@ pub struct HidMiniOutputReport {
     ReportId: ::core::ffi::c_uchar,
     Data: ::core::ffi::c_uchar,
     Pad1: ::core::ffi::c_ushort,
-    Pad2: ::core::ffi::c_ulong,
+    Pad2: u32,
 }
 
-const CONTROL_FEATURE_REPORT_ID: ::core::ffi::c_uchar = 0x01;
+pub const CONTROL_FEATURE_REPORT_ID: ::core::ffi::c_uchar = 0x01;
 const FEATURE_REPORT_SIZE_CB: usize = core::mem::size_of::<HidMiniControlInfo>() - 1;
-const INPUT_REPORT_SIZE_CB: usize = core::mem::size_of::<HidMiniControlInfo>() - 1;
-const OUTPUT_REPORT_SIZE_CB: usize = core::mem::size_of::<HidMiniControlInfo>() - 1;
+const INPUT_REPORT_SIZE_CB: usize = core::mem::size_of::<HidMiniInputReport>() - 1;
+const OUTPUT_REPORT_SIZE_CB: usize = core::mem::size_of::<HidMiniOutputReport>() - 1;
+
+/// Only stores the feature report
+pub const HIDMINI_CONTROL_CODE_NONE: u8 = 0x00;
+/// Delays every following read by `Dummy1` milliseconds, 0 only in tests
+pub const HIDMINI_CONTROL_CODE_SET_READ_DELAY: u8 = 0x01;
+/// Fails the next read
+pub const HIDMINI_CONTROL_CODE_FAIL_NEXT_READ: u8 = 0x02;
+/// Sets the data of the input report to the low byte of `Dummy1`, without queueing it
+pub const HIDMINI_CONTROL_CODE_SET_INPUT: u8 = 0x03;
+
+impl HidMiniControlInfo {
+    pub fn new(control_code: u8, dummy1: u32, dummy2: u32) -> HidMiniControlInfo {
+        HidMiniControlInfo {
+            ReportId: CONTROL_FEATURE_REPORT_ID,
+            ControlCode: control_code,
+            Dummy1: dummy1,
+            Dummy2: dummy2,
+        }
+    }
+
+    /// Parse a feature report without its report id
+    pub fn parse(report: &[u8]) -> Option<HidMiniControlInfo> {
+        let report = report.get(..FEATURE_REPORT_SIZE_CB)?;
+        let ulong = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+        Some(HidMiniControlInfo::new(
+            report[0],
+            ulong(&report[1..5]),
+            ulong(&report[5..9]),
+        ))
+    }
+
+    /// The feature report without its report id
+    pub fn report(&self) -> Vec<u8> {
+        /* copies, packed fields can not be borrowed */
+        let (dummy1, dummy2) = (self.Dummy1, self.Dummy2);
+        let mut report = vec![self.ControlCode];
+        report.extend(dummy1.to_le_bytes());
+        report.extend(dummy2.to_le_bytes());
+        report
+    }
+}
 
 #[rustfmt::skip]
 pub const HID_MINI_REPORT_DESCRIPTOR: &[u8] = &[
@@ -61,8 +116,47 @@ pub const HID_MINI_REPORT_DESCRIPTOR: &[u8] = &[
     0xC0,                           // END_COLLECTION
 ];
 
+struct MiniState {
+    /// Echoed output reports not read yet
+    pending: VecDeque<(u8, Vec<u8>)>,
+    read_delay: Duration,
+    fail_next_read: bool,
+}
+
 pub struct MiniDevice {
     device: RwLock<DeviceData>,
+    state: Mutex<MiniState>,
+}
+
+impl MiniDevice {
+    fn control(&self, info: &HidMiniControlInfo) {
+        let (dummy1, dummy2) = (info.Dummy1, info.Dummy2);
+        debug!(
+            "Control code {:#04x} ({dummy1}, {dummy2})",
+            info.ControlCode
+        );
+
+        match info.ControlCode {
+            HIDMINI_CONTROL_CODE_NONE => {}
+            HIDMINI_CONTROL_CODE_SET_READ_DELAY => {
+                let read_delay = Duration::from_millis(dummy1.into());
+                warn_busy_reads(read_delay);
+                self.state.lock().unwrap().read_delay = read_delay;
+            }
+            HIDMINI_CONTROL_CODE_FAIL_NEXT_READ => self.state.lock().unwrap().fail_next_read = true,
+            HIDMINI_CONTROL_CODE_SET_INPUT => {
+                let reports = &mut self.device.write().unwrap().reports;
+                reports.set(ReportType::Input, CONTROL_FEATURE_REPORT_ID, [dummy1 as u8]);
+            }
+            code => warn!("Ignoring unknown control code {code:#04x}"),
+        }
+    }
+}
+
+fn warn_busy_reads(read_delay: Duration) {
+    if read_delay.is_zero() {
+        warn!("Reads are answered without delay, the driver will read continuously");
+    }
 }
 
 impl Device for MiniDevice {
@@ -70,15 +164,81 @@ impl Device for MiniDevice {
         &self.device
     }
 
+    /// The oldest echoed output report, or the current input report once all are read
     fn read(&self) -> Option<(u8, Vec<u8>)> {
-        None
+        let read_delay = {
+            let mut state = self.state.lock().unwrap();
+            if state.fail_next_read {
+                state.fail_next_read = false;
+                info!("Failing read as requested");
+                return None;
+            }
+            state.read_delay
+        };
+        /* writes are accepted while waiting */
+        thread::sleep(read_delay);
+
+        if let Some(report) = self.state.lock().unwrap().pending.pop_front() {
+            return Some(report);
+        }
+        let data = self.device.read().unwrap();
+        let report = data
+            .reports
+            .get(ReportType::Input, CONTROL_FEATURE_REPORT_ID)?;
+        Some((CONTROL_FEATURE_REPORT_ID, report.into()))
+    }
+
+    fn set_feature(&self, report_id: u8, report: &[u8]) {
+        self.device
+            .write()
+            .unwrap()
+            .reports
+            .set(ReportType::Feature, report_id, report);
+
+        if report_id != CONTROL_FEATURE_REPORT_ID {
+            return;
+        }
+        match HidMiniControlInfo::parse(report) {
+            Some(info) => self.control(&info),
+            None => warn!("Control feature report of {} bytes too short", report.len()),
+        }
+    }
+
+    fn set_output(&self, report_id: u8, report: &[u8]) {
+        let Some(&data) = report.first() else {
+            warn!("Ignoring empty output report {report_id}");
+            return;
+        };
+        let mut device = self.device.write().unwrap();
+        device.reports.set(ReportType::Output, report_id, report);
+
+        /* HidMiniInputReport carries the data of HidMiniOutputReport */
+        let mut state = self.state.lock().unwrap();
+        device.reports.set(ReportType::Input, report_id, [data]);
+        state.pending.push_back((report_id, vec![data]));
     }
 }
 
-pub fn new_mini_device() -> MiniDevice {
+pub const BACKEND: Backend = Backend {
+    name: "mini",
+    description: "Loopback HID device echoing output reports, for testing the driver",
+    schema: ConfigSchema {
+        endpoints: false,
+        options: &[OptionSchema {
+            name: "delay",
+            kind: OptionKind::Seconds,
+            required: false,
+            default: Some("1"),
+            description: "Seconds every read waits, until changed by a control code, 0 only in tests",
+        }],
+    },
+    factory: |config| Ok(Box::new(new_mini_device(config))),
+};
+
+pub fn new_mini_device(device_config: DeviceConfig) -> MiniDevice {
     info!("Creating Mini backend");
     let data = DeviceData {
-        reports: HashMap::new(),
+        reports: Reports::from_descriptor(HID_MINI_REPORT_DESCRIPTOR),
         strings: HashMap::new(),
         vendor_id: 0xDEED,
         product_id: 0xFEED,
@@ -88,7 +248,180 @@ pub fn new_mini_device() -> MiniDevice {
         product: NUT_HID_PRODUCT.into(),
         report_descriptor: HID_MINI_REPORT_DESCRIPTOR.into(),
     };
+    let read_delay = device_config
+        .option("delay")
+        .and_then(parse_seconds)
+        .unwrap_or(Duration::from_secs(1));
+    warn_busy_reads(read_delay);
     MiniDevice {
         device: RwLock::new(data),
+        state: Mutex::new(MiniState {
+            pending: VecDeque::new(),
+            read_delay,
+            fail_next_read: false,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn mini(options: &str) -> MiniDevice {
+        new_mini_device(DeviceConfig::with_options("mini", options))
+    }
+
+    fn control(device: &MiniDevice, info: HidMiniControlInfo) {
+        device.set_feature(CONTROL_FEATURE_REPORT_ID, &info.report());
+    }
+
+    #[test]
+    fn report_sizes() {
+        let sizes = descriptor::report_sizes(HID_MINI_REPORT_DESCRIPTOR);
+        for (report_type, size) in [
+            (ReportType::Feature, 9),
+            (ReportType::Input, 1),
+            (ReportType::Output, 7),
+        ] {
+            assert_eq!(
+                sizes.get(&(report_type, CONTROL_FEATURE_REPORT_ID)),
+                Some(&size),
+                "{report_type:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_control_info() {
+        let info = HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_SET_READ_DELAY, 500, 0x01020304);
+        let report = info.report();
+        assert_eq!(report, [0x01, 0xF4, 0x01, 0, 0, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(HidMiniControlInfo::parse(&report).unwrap().report(), report);
+        assert!(HidMiniControlInfo::parse(&report[..8]).is_none());
+    }
+
+    #[test]
+    fn echo_output_reports() {
+        let device = mini("delay=0");
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0])));
+
+        device.set_output(CONTROL_FEATURE_REPORT_ID, &[0x42, 0, 0, 0, 0, 0, 0]);
+        device.set_output(CONTROL_FEATURE_REPORT_ID, &[0x43, 0, 0, 0, 0, 0, 0]);
+        {
+            let data = device.data().read().unwrap();
+            assert_eq!(
+                data.reports
+                    .get(ReportType::Output, CONTROL_FEATURE_REPORT_ID),
+                Some([0x43, 0, 0, 0, 0, 0, 0].as_slice())
+            );
+            assert_eq!(
+                data.reports
+                    .get(ReportType::Input, CONTROL_FEATURE_REPORT_ID),
+                Some([0x43].as_slice())
+            );
+        }
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x42])));
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x43])));
+        /* the last one repeats once all are read */
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x43])));
+
+        /* an empty report has no data to echo */
+        device.set_output(CONTROL_FEATURE_REPORT_ID, &[]);
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x43])));
+    }
+
+    #[test]
+    fn feature_reports_round_trip() {
+        let device = mini("delay=0");
+        let report = HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_NONE, 7, 8).report();
+        device.set_feature(CONTROL_FEATURE_REPORT_ID, &report);
+        /* other report ids are stored but carry no control code */
+        device.set_feature(2, &[HIDMINI_CONTROL_CODE_FAIL_NEXT_READ, 2]);
+
+        let data = device.data().read().unwrap();
+        assert_eq!(
+            data.reports
+                .get(ReportType::Feature, CONTROL_FEATURE_REPORT_ID),
+            Some(report.as_slice())
+        );
+        assert_eq!(
+            data.reports.get(ReportType::Feature, 2),
+            Some([HIDMINI_CONTROL_CODE_FAIL_NEXT_READ, 2].as_slice())
+        );
+        drop(data);
+        assert!(device.read().is_some());
+    }
+
+    #[test]
+    fn fail_next_read() {
+        let device = mini("delay=0");
+        control(
+            &device,
+            HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_FAIL_NEXT_READ, 0, 0),
+        );
+        assert_eq!(device.read(), None);
+        assert!(device.read().is_some());
+
+        /* a short control report changes nothing */
+        device.set_feature(
+            CONTROL_FEATURE_REPORT_ID,
+            &[HIDMINI_CONTROL_CODE_FAIL_NEXT_READ],
+        );
+        assert!(device.read().is_some());
+    }
+
+    #[test]
+    fn set_read_delay() {
+        let device = mini("delay=0");
+        let read_delay = |device: &MiniDevice| device.state.lock().unwrap().read_delay;
+        control(
+            &device,
+            HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_SET_READ_DELAY, 50, 0),
+        );
+        assert_eq!(read_delay(&device), Duration::from_millis(50));
+        let start = Instant::now();
+        device.read();
+        device.read();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+
+        control(
+            &device,
+            HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_SET_READ_DELAY, 0, 0),
+        );
+        assert_eq!(read_delay(&device), Duration::ZERO);
+    }
+
+    #[test]
+    fn set_input() {
+        let device = mini("delay=0");
+        control(
+            &device,
+            HidMiniControlInfo::new(HIDMINI_CONTROL_CODE_SET_INPUT, 0x1234, 0),
+        );
+        assert_eq!(
+            device
+                .data()
+                .read()
+                .unwrap()
+                .reports
+                .get(ReportType::Input, CONTROL_FEATURE_REPORT_ID),
+            Some([0x34].as_slice())
+        );
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x34])));
+
+        /* unknown codes are stored like any feature report */
+        control(&device, HidMiniControlInfo::new(0x7F, 0, 0));
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![0x34])));
+    }
+
+    #[test]
+    fn mini_from_registry() {
+        let device = registry()
+            .create(DeviceConfig::with_options("mini", "delay=0"))
+            .unwrap();
+        device.set_output(CONTROL_FEATURE_REPORT_ID, &[9, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(device.read(), Some((CONTROL_FEATURE_REPORT_ID, vec![9])));
     }
 }
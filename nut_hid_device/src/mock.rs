@@ -0,0 +1,272 @@
+//! In-process stand-in for upsd, speaking the NUT line protocol on a loopback port.
+//!
+//! Used to exercise the NUT backend without a real server. Available in tests and
+//! through the `mock` feature.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Endpoint;
+use crate::clock::Clock;
+
+/// Misbehavior to inject in place of a normal reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Reply with `ERR <code>`, e.g. `DATA-STALE` or `ACCESS-DENIED`
+    Error(String),
+    /// Close the connection without replying
+    Disconnect,
+}
+
+impl Fault {
+    pub fn error(code: &str) -> Fault {
+        Fault::Error(code.into())
+    }
+}
+
+#[derive(Default)]
+struct MockUps {
+    description: String,
+    variables: BTreeMap<String, String>,
+    faults: BTreeMap<String, Fault>,
+}
+
+#[derive(Default)]
+struct Shared {
+    ups: BTreeMap<String, MockUps>,
+    next_faults: VecDeque<Fault>,
+    commands: Vec<String>,
+    clients: Vec<TcpStream>,
+    connections: usize,
+}
+
+pub struct MockServer {
+    port: u16,
+    shared: Arc<Mutex<Shared>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    /// Start a server on a free loopback port
+    pub fn start() -> MockServer {
+        MockServer::start_on(0)
+    }
+
+    /// Start a server on a specific loopback port, 0 picks a free one
+    pub fn start_on(port: u16) -> MockServer {
+        let mut server = MockServer {
+            port,
+            shared: Default::default(),
+            stopped: Arc::new(AtomicBool::new(true)),
+        };
+        server.listen();
+        server
+    }
+
+    fn listen(&mut self) {
+        let listener = TcpListener::bind(("127.0.0.1", self.port)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        self.port = listener.local_addr().unwrap().port();
+        self.stopped = Arc::new(AtomicBool::new(false));
+
+        let stopped = self.stopped.clone();
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        let mut state = shared.lock().unwrap();
+                        state.connections += 1;
+                        state.clients.push(stream.try_clone().unwrap());
+                        drop(state);
+
+                        let shared = shared.clone();
+                        thread::spawn(move || serve(stream, shared));
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+        });
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new("127.0.0.1", self.port.into())
+    }
+
+    pub fn add_ups(&self, name: &str, description: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.ups.entry(name.into()).or_default().description = description.into();
+    }
+
+    /// Set a variable, creating the ups if needed. Clears any fault on the variable.
+    pub fn set_var(&self, ups: &str, variable: &str, value: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        let ups = shared.ups.entry(ups.into()).or_default();
+        ups.faults.remove(variable);
+        ups.variables.insert(variable.into(), value.into());
+    }
+
+    pub fn remove_var(&self, ups: &str, variable: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(ups) = shared.ups.get_mut(ups) {
+            ups.variables.remove(variable);
+        }
+    }
+
+    /// Answer every request for a variable with a fault until it is set again
+    pub fn fail_var(&self, ups: &str, variable: &str, fault: Fault) {
+        let mut shared = self.shared.lock().unwrap();
+        let ups = shared.ups.entry(ups.into()).or_default();
+        ups.faults.insert(variable.into(), fault);
+    }
+
+    /// Answer the next command, whatever it is, with a fault
+    pub fn fail_next(&self, fault: Fault) {
+        self.shared.lock().unwrap().next_faults.push_back(fault);
+    }
+
+    /// Commands received so far, in order
+    pub fn commands(&self) -> Vec<String> {
+        self.shared.lock().unwrap().commands.clone()
+    }
+
+    /// Number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.shared.lock().unwrap().connections
+    }
+
+    /// Drop all open client connections
+    pub fn disconnect_clients(&self) {
+        for client in self.shared.lock().unwrap().clients.drain(..) {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Stop listening and drop all clients, as if upsd went down
+    pub fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.disconnect_clients();
+        /* let the accept loop notice and drop the listener */
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    /// Listen again on the same port, keeping all scripted state
+    pub fn resume(&mut self) {
+        if self.stopped.load(Ordering::SeqCst) {
+            self.listen();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn serve(stream: TcpStream, shared: Arc<Mutex<Shared>>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        let reply = match handle(&line, &mut shared.lock().unwrap()) {
+            Ok(reply) => reply,
+            Err(Fault::Error(code)) => format!("ERR {code}\n"),
+            Err(Fault::Disconnect) => {
+                let _ = writer.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+        if writer.write_all(reply.as_bytes()).is_err() || line == "LOGOUT" {
+            return;
+        }
+    }
+}
+
+fn handle(line: &str, shared: &mut Shared) -> Result<String, Fault> {
+    shared.commands.push(line.into());
+    if let Some(fault) = shared.next_faults.pop_front() {
+        return Err(fault);
+    }
+
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    match args[..] {
+        ["LIST", "UPS"] => {
+            let mut reply = String::from("BEGIN LIST UPS\n");
+            for (name, ups) in &shared.ups {
+                reply += &format!("UPS {} {}\n", name, quote(&ups.description));
+            }
+            Ok(reply + "END LIST UPS\n")
+        }
+        ["LIST", "VAR", name] => {
+            let ups = shared.ups.get(name).ok_or(Fault::error("UNKNOWN-UPS"))?;
+            let mut reply = format!("BEGIN LIST VAR {name}\n");
+            for (variable, value) in &ups.variables {
+                reply += &format!("VAR {} {} {}\n", name, variable, quote(value));
+            }
+            Ok(reply + &format!("END LIST VAR {name}\n"))
+        }
+        ["GET", "VAR", name, variable] => {
+            let ups = shared.ups.get(name).ok_or(Fault::error("UNKNOWN-UPS"))?;
+            if let Some(fault) = ups.faults.get(variable) {
+                return Err(fault.clone());
+            }
+            let value = ups
+                .variables
+                .get(variable)
+                .ok_or(Fault::error("VAR-NOT-SUPPORTED"))?;
+            Ok(format!("VAR {} {} {}\n", name, variable, quote(value)))
+        }
+        ["USERNAME", _] | ["PASSWORD", _] | ["LOGOUT"] => Ok("OK\n".into()),
+        ["VER"] => Ok("Network UPS Tools upsd mock\n".into()),
+        ["NETVER"] => Ok("1.3\n".into()),
+        _ => Err(Fault::error("UNKNOWN-COMMAND")),
+    }
+}
+
+/// Clock that only moves when advanced
+pub struct FakeClock {
+    now: Mutex<Instant>,
+}
+
+impl FakeClock {
+    pub fn new() -> Arc<FakeClock> {
+        Arc::new(FakeClock {
+            now: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    /* returns at once, as if the time had passed */
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,499 @@
+//! Backend playing the device dumps of dummy-ups, the simulation driver of NUT.
+//!
+//! A `.dev` file lists the variables of a ups as `upsc` prints them, which is the
+//! format of the NUT device dump library. A `.seq` file adds `TIMER` lines, each one
+//! holds the values set so far for that many seconds before the lines after it apply:
+//!
+//! ```text
+//! battery.charge: 100
+//! ups.status: OL
+//! TIMER 10
+//! ups.status: OB DISCHRG
+//! battery.charge: "72"
+//! TIMER 30
+//! ```
+//!
+//! Like dummy-ups the file starts over once its end is reached, the values set before
+//! are kept. Lines starting with `#` are comments.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::*;
+use clock::{Clock, SystemClock};
+use log::{debug, info};
+use mapping::MappingTable;
+use nut::{HostLimits, StatusFilter, new_nut_device_data};
+use replay::{Snapshot, snapshot_reports, unquote};
+
+/// Values of a dump file and how long each of them is held
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Dump {
+    /// Variables from the start of the file up to each `TIMER`, starting at `elapsed`
+    pub states: Vec<Snapshot>,
+    /// Sum of the timers
+    pub length: Duration,
+}
+
+impl Dump {
+    /// Time a state is held, the last one without a `TIMER` is held for `poll_interval`
+    pub fn hold(&self, index: usize, poll_interval: Duration) -> Duration {
+        let end = match self.states.get(index + 1) {
+            Some(next) => next.elapsed,
+            None => self.length,
+        };
+        match end.saturating_sub(self.states[index].elapsed) {
+            Duration::ZERO => poll_interval,
+            hold => hold,
+        }
+    }
+}
+
+pub fn read_dump(reader: impl BufRead) -> io::Result<Dump> {
+    let mut dump = Dump::default();
+    let mut variables = BTreeMap::new();
+    /* set since the last timer */
+    let mut changed = false;
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid dump at line {}: {}", number + 1, line),
+            )
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(seconds) = line.strip_prefix("TIMER ") {
+            let timer = parse_seconds(seconds.trim()).ok_or_else(invalid)?;
+            dump.states.push(Snapshot {
+                elapsed: dump.length,
+                variables: variables.clone(),
+                error: None,
+            });
+            dump.length += timer;
+            changed = false;
+            continue;
+        }
+
+        let (variable, value) = line.split_once(':').ok_or_else(invalid)?;
+        if variable.is_empty() || variable.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        let value = value.trim();
+        let value = match value.starts_with('"') {
+            true => unquote(value).ok_or_else(invalid)?,
+            false => value.into(),
+        };
+        variables.insert(variable.into(), Some(value));
+        changed = true;
+    }
+
+    if variables.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Dump without variables",
+        ));
+    }
+    if changed {
+        dump.states.push(Snapshot {
+            elapsed: dump.length,
+            variables,
+            error: None,
+        });
+    }
+    Ok(dump)
+}
+
+pub const BACKEND: Backend = Backend {
+    name: "nutdump",
+    description: "Plays a dummy-ups device dump (.dev) or sequence (.seq) of NUT",
+    schema: ConfigSchema {
+        endpoints: false,
+        options: &[
+            OptionSchema {
+                name: "file",
+                kind: OptionKind::Text,
+                required: true,
+                default: None,
+                description: "The .dev or .seq file to play",
+            },
+            OptionSchema {
+                name: "speed",
+                kind: OptionKind::Number,
+                required: false,
+                default: Some("1"),
+                description: "Playback speed, 0 plays without delays",
+            },
+            OptionSchema {
+                name: "poll",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("10"),
+                description: "Seconds values without a TIMER are held",
+            },
+            OptionSchema {
+                name: "mapping",
+                kind: OptionKind::Text,
+                required: false,
+                default: None,
+                description: "TOML file mapping NUT variables to HID usages",
+            },
+            OptionSchema {
+                name: "grace",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("10"),
+                description: "Time to keep the last status before reporting lost communication",
+            },
+            OptionSchema {
+                name: "debounce",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("0"),
+                description: "Time a change between line and battery power must last",
+            },
+        ],
+    },
+    factory: |config| Ok(Box::new(new_nutdump_device(config)?)),
+};
+
+struct NutDumpState {
+    pending: VecDeque<(u8, Vec<u8>)>,
+    /// State reported last, none before the first read
+    index: Option<usize>,
+    /// Time the states have been held so far, the status is filtered at this time
+    played: Duration,
+    filter: StatusFilter,
+    start: Instant,
+}
+
+pub struct NutDumpDevice {
+    device: RwLock<DeviceData>,
+    state: Mutex<NutDumpState>,
+    dump: Dump,
+    mapping: MappingTable,
+    limits: Mutex<HostLimits>,
+    /// Playback speed relative to the timers, 0 plays without delays
+    speed: f64,
+    poll_interval: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Device for NutDumpDevice {
+    fn data(&self) -> &RwLock<DeviceData> {
+        &self.device
+    }
+
+    fn read(&self) -> Option<(u8, Vec<u8>)> {
+        /* get all pending */
+        let mut state = self.state.lock().unwrap();
+        if let Some(report) = state.pending.pop_front() {
+            return Some(report);
+        }
+
+        let index = match state.index {
+            Some(index) => {
+                let hold = self.dump.hold(index, self.poll_interval);
+                state.played += hold;
+                if self.speed > 0.0 {
+                    self.clock.sleep(hold.div_f64(self.speed));
+                }
+                let next = (index + 1) % self.dump.states.len();
+                if next == 0 {
+                    debug!("Dump finished, starting over");
+                }
+                next
+            }
+            None => 0,
+        };
+        state.index = Some(index);
+
+        let mut snapshot = self.dump.states[index].clone();
+        let limits = *self.limits.lock().unwrap();
+        let now = state.start + state.played;
+        let reports = snapshot_reports(
+            &mut snapshot,
+            &self.mapping,
+            &limits,
+            &mut state.filter,
+            now,
+        );
+        let mut device = self.device.write().unwrap();
+        device.publish(&mut state.pending, reports);
+        state.pending.pop_front()
+    }
+
+    fn set_feature(&self, report_id: u8, report: &[u8]) {
+        self.limits.lock().unwrap().set(report_id, report);
+        let reports = &mut self.device.write().unwrap().reports;
+        reports.set(ReportType::Feature, report_id, report);
+    }
+}
+
+pub fn new_nutdump_device(device_config: DeviceConfig) -> Result<NutDumpDevice, DeviceError> {
+    info!("Creating NUT dump backend");
+
+    let path = device_config
+        .option("file")
+        .ok_or(DeviceError::InvalidConfig(vec![
+            ConfigError::MissingOption("file".into()),
+        ]))?;
+
+    let dump = File::open(path)
+        .and_then(|file| read_dump(BufReader::new(file)))
+        .map_err(|err| DeviceError::backend("nutdump", err))?;
+    info!(
+        "Playing {} states over {:?} from {path}",
+        dump.states.len(),
+        dump.length
+    );
+    let mapping = MappingTable::from_config(&device_config)?;
+
+    Ok(NutDumpDevice {
+        device: RwLock::new(new_nut_device_data()),
+        state: Mutex::new(NutDumpState {
+            pending: VecDeque::new(),
+            index: None,
+            played: Duration::ZERO,
+            filter: StatusFilter::from_config(&device_config),
+            start: Instant::now(),
+        }),
+        dump,
+        mapping,
+        limits: Mutex::default(),
+        speed: device_config.option_parse("speed").unwrap_or(1.0),
+        poll_interval: device_config
+            .option("poll")
+            .and_then(parse_seconds)
+            .unwrap_or(Duration::from_secs(10)),
+        clock: Arc::new(SystemClock),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::FakeClock;
+    use decode::{Reading, lookup};
+
+    const DEV: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/dumps/apc-back-ups-es.dev");
+    const SEQ: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/dumps/power-failure.seq");
+
+    fn play(path: &str, options: &str) -> NutDumpDevice {
+        let mut config = DeviceConfig::with_options("nutdump", options);
+        config.options.insert("file".into(), path.into());
+        new_nutdump_device(config).unwrap()
+    }
+
+    fn reading(device: &NutDumpDevice, path: &str) -> Reading {
+        lookup(&device.data().read().unwrap(), path)
+            .unwrap()
+            .reading
+    }
+
+    /* reads the reports of one state, the status comes last */
+    fn read_state(device: &NutDumpDevice) -> Vec<(u8, Vec<u8>)> {
+        let status = nut::PresentStatus::default().report().0;
+        let mut reports = Vec::new();
+        loop {
+            let report = device.read().unwrap();
+            let last = report.0 == status;
+            reports.push(report);
+            if last {
+                return reports;
+            }
+        }
+    }
+
+    #[test]
+    fn parse_dump() {
+        let data = "\
+# comment
+battery.charge: 100
+ups.model: \"Back-UPS ES 700G\"
+ups.status: OL
+TIMER 10
+ups.status: OB DISCHRG
+TIMER 2.5
+";
+        let dump = read_dump(data.as_bytes()).unwrap();
+        let state = |charge: &str, model: &str, status: &str| {
+            BTreeMap::from([
+                ("battery.charge".to_string(), Some(charge.to_string())),
+                ("ups.model".to_string(), Some(model.to_string())),
+                ("ups.status".to_string(), Some(status.to_string())),
+            ])
+        };
+        assert_eq!(dump.length, Duration::from_millis(12500));
+        assert_eq!(dump.states.len(), 2);
+        assert_eq!(dump.states[0].elapsed, Duration::ZERO);
+        assert_eq!(
+            dump.states[0].variables,
+            state("100", "Back-UPS ES 700G", "OL")
+        );
+        assert_eq!(dump.states[1].elapsed, Duration::from_secs(10));
+        assert_eq!(
+            dump.states[1].variables,
+            state("100", "Back-UPS ES 700G", "OB DISCHRG")
+        );
+
+        let poll = Duration::from_secs(30);
+        assert_eq!(dump.hold(0, poll), Duration::from_secs(10));
+        assert_eq!(dump.hold(1, poll), Duration::from_millis(2500));
+
+        /* values after the last timer are held for the poll interval */
+        let dump = read_dump("battery.charge: 80\nTIMER 5\nbattery.charge: 70\n".as_bytes());
+        let dump = dump.unwrap();
+        assert_eq!(dump.states.len(), 2);
+        assert_eq!(dump.hold(1, poll), poll);
+    }
+
+    #[test]
+    fn read_invalid_dumps() {
+        for data in [
+            "",
+            "# only a comment\n",
+            "TIMER 5\n",
+            "battery.charge 100\n",
+            "battery charge: 100\n",
+            ": 100\n",
+            "ups.model: \"unterminated\n",
+            "battery.charge: 100\nTIMER soon\n",
+        ] {
+            assert!(read_dump(data.as_bytes()).is_err(), "{data}");
+        }
+    }
+
+    #[test]
+    fn play_device_dump() {
+        let dump = read_dump(BufReader::new(File::open(DEV).unwrap())).unwrap();
+        assert_eq!(dump.states.len(), 1);
+        assert_eq!(dump.states[0].variables.len(), 39);
+        assert_eq!(
+            dump.states[0].variables["ups.model"].as_deref(),
+            Some("Back-UPS ES 700G")
+        );
+
+        let device = play(DEV, "speed=0");
+        let reports = read_state(&device);
+        assert_eq!(
+            reading(&device, "RemainingCapacity"),
+            Reading::Number(100.0)
+        );
+        assert_eq!(
+            reading(&device, "RemainingCapacityLimit"),
+            Reading::Number(10.0)
+        );
+        assert_eq!(reading(&device, "RunTimeToEmpty"), Reading::Number(38.0));
+        assert_eq!(reading(&device, "ACPresent"), Reading::Flag(true));
+        assert_eq!(reading(&device, "Discharging"), Reading::Flag(false));
+
+        /* a dump without timers keeps reporting the same values */
+        assert_eq!(read_state(&device), reports);
+    }
+
+    #[test]
+    fn play_sequence() {
+        let device = play(SEQ, "speed=0");
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            read_state(&device);
+            states.push((
+                reading(&device, "RemainingCapacity"),
+                reading(&device, "ACPresent"),
+                reading(&device, "Discharging"),
+                reading(&device, "BelowRemainingCapacityLimit"),
+            ));
+        }
+
+        use Reading::{Flag, Number};
+        assert_eq!(
+            states,
+            [
+                (Number(100.0), Flag(true), Flag(false), Flag(false)),
+                (Number(72.0), Flag(false), Flag(true), Flag(false)),
+                (Number(15.0), Flag(false), Flag(true), Flag(true)),
+                (Number(16.0), Flag(true), Flag(false), Flag(true)),
+                /* starts over */
+                (Number(100.0), Flag(true), Flag(false), Flag(false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn host_limits() {
+        let device = play(SEQ, "speed=0");
+        read_state(&device);
+
+        /* the second state is at 72 percent, above the limit of the ups */
+        let limit = decode::find(nut::UPS_REPORT_DESCRIPTOR, "RemainingCapacityLimit").unwrap();
+        device.set_feature(limit.report_id, &[80]);
+        read_state(&device);
+        assert_eq!(
+            reading(&device, "RemainingCapacityLimit"),
+            Reading::Number(80.0)
+        );
+        assert_eq!(
+            reading(&device, "BelowRemainingCapacityLimit"),
+            Reading::Flag(true)
+        );
+    }
+
+    #[test]
+    fn sequence_timing() {
+        let data = "battery.charge: 90\nTIMER 10\nbattery.charge: 80\nTIMER 10\n";
+        let path = std::env::temp_dir().join(format!(
+            "nut_hid_{}_sequence_timing.seq",
+            std::process::id()
+        ));
+        std::fs::write(&path, data).unwrap();
+        let mut device = play(path.to_str().unwrap(), "speed=100");
+        std::fs::remove_file(&path).unwrap();
+        let clock = FakeClock::new();
+        device.clock = clock.clone();
+
+        let start = clock.now();
+        read_state(&device);
+        read_state(&device);
+        read_state(&device);
+        /* both timers at 100 times the speed */
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+        assert_eq!(reading(&device, "RemainingCapacity"), Reading::Number(90.0));
+    }
+
+    #[test]
+    fn every_dump_plays() {
+        let directory = concat!(env!("CARGO_MANIFEST_DIR"), "/dumps");
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let device = play(path.to_str().unwrap(), "speed=0");
+            let dump = &device.dump;
+            assert!(!dump.states.is_empty(), "{path:?}");
+            for _ in &dump.states {
+                read_state(&device);
+            }
+        }
+    }
+
+    #[test]
+    fn nutdump_missing_file() {
+        assert!(matches!(
+            new_nutdump_device(Default::default()),
+            Err(DeviceError::InvalidConfig(_))
+        ));
+        let config = DeviceConfig::with_options("nutdump", "file=/nonexistent/ups.dev");
+        assert!(matches!(
+            new_nutdump_device(config),
+            Err(DeviceError::Backend { .. })
+        ));
+    }
+}
@@ -0,0 +1,360 @@
+//! Registry of the available backends.
+//!
+//! Each backend registers a name, a description, the configuration it accepts and a
+//! factory. `from_config` looks the backend up by name, so adding a backend only
+//! needs a `Backend` entry and a call to `Registry::register`.
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use super::*;
+use fault::{FAULT_OPTIONS, FaultDevice, Faults};
+
+/// Kind of value an option accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionKind {
+    /// Any text, e.g. a path
+    Text,
+    /// Non negative number of seconds, fractions allowed
+    Seconds,
+    /// Non negative number
+    Number,
+    /// Non negative whole number, e.g. a count of reports
+    Integer,
+    /// `0`, `1`, `false` or `true`
+    Flag,
+}
+
+impl OptionKind {
+    pub fn is_valid(&self, value: &str) -> bool {
+        match self {
+            OptionKind::Text => true,
+            OptionKind::Seconds => parse_seconds(value).is_some(),
+            OptionKind::Number => value.parse::<f64>().is_ok_and(|value| value >= 0.0),
+            OptionKind::Integer => value.parse::<u64>().is_ok(),
+            OptionKind::Flag => parse_flag(value).is_some(),
+        }
+    }
+}
+
+impl fmt::Display for OptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionKind::Text => write!(f, "text"),
+            OptionKind::Seconds => write!(f, "seconds"),
+            OptionKind::Number => write!(f, "number"),
+            OptionKind::Integer => write!(f, "integer"),
+            OptionKind::Flag => write!(f, "0|1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OptionSchema {
+    pub name: &'static str,
+    pub kind: OptionKind,
+    pub required: bool,
+    /// Value used when the option is not given
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+}
+
+/// Configuration accepted by a backend
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigSchema {
+    /// Whether the backend connects to the configured hosts
+    pub endpoints: bool,
+    pub options: &'static [OptionSchema],
+}
+
+/// Options accepted by every backend, overriding the identity of the device
+pub const COMMON_OPTIONS: &[OptionSchema] = &[
+    OptionSchema {
+        name: "manufacturer",
+        kind: OptionKind::Text,
+        required: false,
+        default: None,
+        description: "Manufacturer reported to Windows",
+    },
+    OptionSchema {
+        name: "product",
+        kind: OptionKind::Text,
+        required: false,
+        default: None,
+        description: "Product name reported to Windows",
+    },
+    OptionSchema {
+        name: "serial",
+        kind: OptionKind::Text,
+        required: false,
+        default: None,
+        description: "Serial number reported to Windows",
+    },
+];
+
+impl ConfigSchema {
+    pub fn validate(&self, config: &DeviceConfig, errors: &mut Vec<ConfigError>) {
+        if self.endpoints {
+            config.check_endpoints(errors);
+        }
+
+        let options = || {
+            self.options
+                .iter()
+                .chain(COMMON_OPTIONS)
+                .chain(FAULT_OPTIONS)
+        };
+        let known = options().map(|option| option.name).collect::<Vec<_>>();
+        config.check_options(&known, errors);
+
+        for option in options() {
+            if option.required {
+                config.require_option(option.name, errors);
+            }
+            config.check_option(option.name, |value| option.kind.is_valid(value), errors);
+        }
+    }
+}
+
+pub type Factory = fn(DeviceConfig) -> Result<Box<dyn Device + Send + Sync>, DeviceError>;
+
+#[derive(Clone, Copy)]
+pub struct Backend {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub schema: ConfigSchema,
+    pub factory: Factory,
+}
+
+pub struct Registry {
+    backends: BTreeMap<&'static str, Backend>,
+}
+
+impl Registry {
+    /// An empty registry, see `Registry::default` for one with the built-in backends
+    pub fn new() -> Registry {
+        Registry {
+            backends: BTreeMap::new(),
+        }
+    }
+
+    /// Add a backend, replacing any backend with the same name
+    pub fn register(&mut self, backend: Backend) {
+        self.backends.insert(backend.name, backend);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Backend> {
+        self.backends.get(name)
+    }
+
+    /// All backends, ordered by name
+    pub fn backends(&self) -> impl Iterator<Item = &Backend> {
+        self.backends.values()
+    }
+
+    /// Check the configuration, reporting every problem found
+    pub fn validate(&self, config: &DeviceConfig) -> Result<(), DeviceError> {
+        let mut errors = Vec::new();
+        match self.get(&config.backend) {
+            Some(backend) => backend.schema.validate(config, &mut errors),
+            None => errors.push(ConfigError::UnknownBackend(config.backend.clone())),
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidConfig(errors))
+        }
+    }
+
+    pub fn create(
+        &self,
+        config: DeviceConfig,
+    ) -> Result<Box<dyn Device + Send + Sync>, DeviceError> {
+        let backend = self
+            .get(&config.backend)
+            .ok_or_else(|| DeviceError::InvalidBackend(config.backend.clone()))?;
+        self.validate(&config)?;
+
+        let identity = Identity::from_config(&config);
+        let faults = Faults::from_config(&config);
+        let mut device = (backend.factory)(config)?;
+        identity.apply(&mut device.data().write().unwrap());
+        if faults.any() {
+            device = Box::new(FaultDevice::new(device, faults)?);
+        }
+        Ok(device)
+    }
+}
+
+struct Identity {
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+}
+
+impl Identity {
+    fn from_config(config: &DeviceConfig) -> Identity {
+        Identity {
+            manufacturer: config.option("manufacturer").map(String::from),
+            product: config.option("product").map(String::from),
+            serial_number: config.option("serial").map(String::from),
+        }
+    }
+
+    fn apply(self, data: &mut DeviceData) {
+        if let Some(manufacturer) = self.manufacturer {
+            data.manufacturer = manufacturer;
+        }
+        if let Some(product) = self.product {
+            data.product = product;
+        }
+        if let Some(serial_number) = self.serial_number {
+            data.serial_number = serial_number;
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        let mut registry = Registry::new();
+        registry.register(nut::BACKEND);
+        registry.register(dummy::BACKEND);
+        registry.register(mini::BACKEND);
+        registry.register(nutdump::BACKEND);
+        registry.register(replay::BACKEND);
+        registry.register(sim::BACKEND);
+        registry
+    }
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// Registry with the built-in backends
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NullDevice {
+        device: RwLock<DeviceData>,
+    }
+
+    impl Device for NullDevice {
+        fn data(&self) -> &RwLock<DeviceData> {
+            &self.device
+        }
+
+        fn read(&self) -> Option<(u8, Vec<u8>)> {
+            None
+        }
+    }
+
+    fn create_null(_config: DeviceConfig) -> Result<Box<dyn Device + Send + Sync>, DeviceError> {
+        Ok(Box::new(NullDevice {
+            device: Default::default(),
+        }))
+    }
+
+    const NULL: Backend = Backend {
+        name: "null",
+        description: "Does nothing",
+        schema: ConfigSchema {
+            endpoints: false,
+            options: &[OptionSchema {
+                name: "delay",
+                kind: OptionKind::Seconds,
+                required: true,
+                default: None,
+                description: "Delay",
+            }],
+        },
+        factory: create_null,
+    };
+
+    #[test]
+    fn builtin_backends() {
+        let names = registry()
+            .backends()
+            .map(|backend| backend.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["dummy", "mini", "nut", "nutdump", "replay", "sim"]);
+    }
+
+    #[test]
+    fn register_backend() {
+        let mut registry = Registry::new();
+        assert!(matches!(
+            registry.create(DeviceConfig::with_options("null", "delay=1")),
+            Err(DeviceError::InvalidBackend(_))
+        ));
+
+        registry.register(NULL);
+        let device = registry
+            .create(DeviceConfig::with_options("null", "delay=1"))
+            .unwrap();
+        assert!(device.read().is_none());
+    }
+
+    #[test]
+    fn identity_overrides() {
+        let config = DeviceConfig::parse_url("dummy:?product=Rack%201&serial=A1").unwrap();
+        let device = registry().create(config).unwrap();
+        let data = device.data().read().unwrap();
+        assert_eq!(data.product, "Rack 1");
+        assert_eq!(data.serial_number, "A1");
+        assert_eq!(data.manufacturer, constants::NUT_HID_MANUFACTURER);
+    }
+
+    #[test]
+    fn validate_schema() {
+        let mut registry = Registry::new();
+        registry.register(NULL);
+
+        assert!(
+            registry
+                .validate(&DeviceConfig::with_options("null", "delay=0.5"))
+                .is_ok()
+        );
+
+        let Err(DeviceError::InvalidConfig(errors)) =
+            registry.validate(&DeviceConfig::with_options("null", "delay=soon;size=2"))
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::UnknownOption("size".into()),
+                ConfigError::InvalidOption {
+                    name: "delay".into(),
+                    value: "soon".into()
+                },
+            ]
+        );
+
+        let Err(DeviceError::InvalidConfig(errors)) =
+            registry.validate(&DeviceConfig::with_options("null", ""))
+        else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(errors, vec![ConfigError::MissingOption("delay".into())]);
+    }
+
+    #[test]
+    fn option_kind() {
+        assert!(OptionKind::Text.is_valid(""));
+        assert!(OptionKind::Seconds.is_valid("2.5"));
+        assert!(!OptionKind::Seconds.is_valid("-1"));
+        assert!(OptionKind::Number.is_valid("0"));
+        assert!(!OptionKind::Number.is_valid("fast"));
+        assert!(OptionKind::Integer.is_valid("3"));
+        assert!(!OptionKind::Integer.is_valid("2.5"));
+        assert!(!OptionKind::Integer.is_valid("-1"));
+        assert!(OptionKind::Flag.is_valid("true"));
+        assert!(!OptionKind::Flag.is_valid("yes"));
+    }
+}
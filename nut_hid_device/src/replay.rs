@@ -0,0 +1,560 @@
+//! Recording of NUT variable snapshots and a backend replaying them.
+//!
+//! A recording is a text file with one block per poll:
+//!
+//! ```text
+//! snapshot 2004
+//! var battery.charge "80"
+//! unsupported battery.runtime
+//! var ups.status "OL"
+//! ```
+//!
+//! The number after `snapshot` is milliseconds since the recording started. A poll
+//! that ended without any data is stored as a snapshot with an `error "..."` line.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::*;
+use clock::{Clock, SystemClock};
+use log::{debug, error, info, warn};
+use mapping::MappingTable;
+use nut::{HostLimits, StatusFilter, Variables, new_nut_device_data, update};
+use rups::ClientError;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Time since the start of the recording
+    pub elapsed: Duration,
+    /// Variables read during the poll, `None` if not supported by the ups
+    pub variables: BTreeMap<String, Option<String>>,
+    /// Set if the poll failed
+    pub error: Option<String>,
+}
+
+impl Variables for Snapshot {
+    fn get_str(&mut self, variable: &str) -> Result<Option<String>, ClientError> {
+        Ok(self.variables.get(variable).cloned().flatten())
+    }
+}
+
+/// Wraps a variable source, capturing every value read into a snapshot
+pub struct Recording<'a> {
+    source: &'a mut dyn Variables,
+    pub snapshot: Snapshot,
+}
+
+impl<'a> Recording<'a> {
+    pub fn new(source: &'a mut dyn Variables) -> Recording<'a> {
+        Recording {
+            source,
+            snapshot: Snapshot::default(),
+        }
+    }
+}
+
+impl Variables for Recording<'_> {
+    fn get_str(&mut self, variable: &str) -> Result<Option<String>, ClientError> {
+        let value = self.source.get_str(variable)?;
+        self.snapshot
+            .variables
+            .insert(variable.into(), value.clone());
+        Ok(value)
+    }
+}
+
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Recorder> {
+        Ok(Recorder::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(writer: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            writer: Box::new(writer),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, mut snapshot: Snapshot) {
+        snapshot.elapsed = self.start.elapsed();
+        if let Err(err) = write_snapshot(&mut self.writer, &snapshot) {
+            warn!("Failed to write recording: {err}");
+        }
+    }
+
+    pub fn record_error(&mut self, error: &str) {
+        self.record(Snapshot {
+            error: Some(error.into()),
+            ..Default::default()
+        });
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub(crate) fn unquote(value: &str) -> Option<String> {
+    let value = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.push(chars.next()?),
+            '"' => return None,
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+pub fn write_snapshot(writer: &mut dyn Write, snapshot: &Snapshot) -> io::Result<()> {
+    writeln!(writer, "snapshot {}", snapshot.elapsed.as_millis())?;
+    for (variable, value) in &snapshot.variables {
+        match value {
+            Some(value) => writeln!(writer, "var {} {}", variable, quote(value))?,
+            None => writeln!(writer, "unsupported {}", variable)?,
+        }
+    }
+    if let Some(error) = &snapshot.error {
+        writeln!(writer, "error {}", quote(error))?;
+    }
+    writeln!(writer)?;
+    writer.flush()
+}
+
+pub fn read_snapshots(reader: impl BufRead) -> io::Result<Vec<Snapshot>> {
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid recording at line {}: {}", number + 1, line),
+            )
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if keyword == "snapshot" {
+            let elapsed = rest.parse().map_err(|_| invalid())?;
+            snapshots.push(Snapshot {
+                elapsed: Duration::from_millis(elapsed),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let snapshot = snapshots.last_mut().ok_or_else(invalid)?;
+        match keyword {
+            "var" => {
+                let (variable, value) = rest.split_once(' ').ok_or_else(invalid)?;
+                let value = unquote(value).ok_or_else(invalid)?;
+                snapshot.variables.insert(variable.into(), Some(value));
+            }
+            "unsupported" if !rest.is_empty() => {
+                snapshot.variables.insert(rest.into(), None);
+            }
+            "error" => {
+                snapshot.error = Some(unquote(rest).ok_or_else(invalid)?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(snapshots)
+}
+
+pub const BACKEND: Backend = Backend {
+    name: "replay",
+    description: "Replays a recording made by the nut backend",
+    schema: ConfigSchema {
+        endpoints: false,
+        options: &[
+            OptionSchema {
+                name: "file",
+                kind: OptionKind::Text,
+                required: true,
+                default: None,
+                description: "Recording to replay",
+            },
+            OptionSchema {
+                name: "speed",
+                kind: OptionKind::Number,
+                required: false,
+                default: Some("1"),
+                description: "Playback speed, 0 replays without delays",
+            },
+            OptionSchema {
+                name: "mapping",
+                kind: OptionKind::Text,
+                required: false,
+                default: None,
+                description: "TOML file mapping NUT variables to HID usages",
+            },
+            OptionSchema {
+                name: "grace",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("10"),
+                description: "Time to keep the last status before reporting lost communication",
+            },
+            OptionSchema {
+                name: "debounce",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("0"),
+                description: "Time a change between line and battery power must last",
+            },
+        ],
+    },
+    factory: |config| Ok(Box::new(new_replay_device(config)?)),
+};
+
+struct ReplayState {
+    pending: VecDeque<(u8, Vec<u8>)>,
+    snapshots: VecDeque<Snapshot>,
+    elapsed: Duration,
+    filter: StatusFilter,
+    /// Time the recording started, snapshots are filtered at their recorded time
+    start: Instant,
+}
+
+pub struct ReplayDevice {
+    device: RwLock<DeviceData>,
+    state: Mutex<ReplayState>,
+    mapping: MappingTable,
+    limits: Mutex<HostLimits>,
+    /// Playback speed relative to the recording, 0 replays without delays
+    speed: f64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Device for ReplayDevice {
+    fn data(&self) -> &RwLock<DeviceData> {
+        &self.device
+    }
+
+    fn read(&self) -> Option<(u8, Vec<u8>)> {
+        /* get all pending */
+        let mut state = self.state.lock().unwrap();
+        if let Some(report) = state.pending.pop_front() {
+            return Some(report);
+        }
+
+        let Some(mut snapshot) = state.snapshots.pop_front() else {
+            debug!("Replay finished");
+            return None;
+        };
+
+        let delay = snapshot.elapsed.saturating_sub(state.elapsed);
+        state.elapsed = snapshot.elapsed;
+        if self.speed > 0.0 {
+            self.clock.sleep(delay.div_f64(self.speed));
+        }
+
+        let limits = *self.limits.lock().unwrap();
+        let now = state.start + snapshot.elapsed;
+        let reports = snapshot_reports(
+            &mut snapshot,
+            &self.mapping,
+            &limits,
+            &mut state.filter,
+            now,
+        );
+        let mut device = self.device.write().unwrap();
+        device.publish(&mut state.pending, reports);
+        state.pending.pop_front()
+    }
+
+    fn set_feature(&self, report_id: u8, report: &[u8]) {
+        self.limits.lock().unwrap().set(report_id, report);
+        let reports = &mut self.device.write().unwrap().reports;
+        reports.set(ReportType::Feature, report_id, report);
+    }
+}
+
+/// Input reports of a snapshot mapped and filtered like the nut backend handles a poll
+pub(crate) fn snapshot_reports(
+    snapshot: &mut Snapshot,
+    mapping: &MappingTable,
+    limits: &HostLimits,
+    filter: &mut StatusFilter,
+    now: Instant,
+) -> Vec<(u8, Vec<u8>)> {
+    if let Some(err) = &snapshot.error {
+        error!("Replaying failed poll: {err}");
+        return vec![filter.failed(now).report()];
+    }
+
+    let mut reports = Vec::new();
+    match update(snapshot, mapping, limits, &mut reports) {
+        Ok(status) => {
+            reports.push(filter.update(status, now).report());
+            reports
+        }
+        Err(err) => {
+            error!("Failed to replay snapshot: {err}");
+            vec![filter.failed(now).report()]
+        }
+    }
+}
+
+pub fn new_replay_device(device_config: DeviceConfig) -> Result<ReplayDevice, DeviceError> {
+    info!("Creating Replay backend");
+
+    let path = device_config
+        .option("file")
+        .ok_or(DeviceError::InvalidConfig(vec![
+            ConfigError::MissingOption("file".into()),
+        ]))?;
+
+    let snapshots = File::open(path)
+        .and_then(|file| read_snapshots(BufReader::new(file)))
+        .map_err(|err| DeviceError::backend("replay", err))?;
+    info!("Replaying {} snapshots from {path}", snapshots.len());
+    let mapping = MappingTable::from_config(&device_config)?;
+
+    Ok(ReplayDevice {
+        device: RwLock::new(new_nut_device_data()),
+        state: Mutex::new(ReplayState {
+            pending: VecDeque::new(),
+            snapshots: snapshots.into(),
+            elapsed: Duration::ZERO,
+            filter: StatusFilter::from_config(&device_config),
+            start: Instant::now(),
+        }),
+        mapping,
+        limits: Mutex::default(),
+        speed: device_config.option_parse("speed").unwrap_or(1.0),
+        clock: Arc::new(SystemClock),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{FakeClock, Fault, MockServer};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nut_hid_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let snapshots = vec![
+            Snapshot {
+                elapsed: Duration::from_millis(2004),
+                variables: BTreeMap::from([
+                    ("battery.charge".into(), Some("80".into())),
+                    ("battery.runtime".into(), None),
+                    ("ups.status".into(), Some("OL \"quoted\" \\".into())),
+                ]),
+                error: None,
+            },
+            Snapshot {
+                elapsed: Duration::from_millis(4010),
+                error: Some("No NUT endpoint available".into()),
+                ..Default::default()
+            },
+        ];
+
+        let mut data = Vec::new();
+        for snapshot in &snapshots {
+            write_snapshot(&mut data, snapshot).unwrap();
+        }
+
+        assert_eq!(read_snapshots(data.as_slice()).unwrap(), snapshots);
+    }
+
+    #[test]
+    fn read_invalid_snapshots() {
+        for data in [
+            "var battery.charge \"80\"\n",
+            "snapshot soon\n",
+            "snapshot 0\nvar battery.charge 80\n",
+            "snapshot 0\nunknown\n",
+        ] {
+            assert!(read_snapshots(data.as_bytes()).is_err(), "{data}");
+        }
+    }
+
+    #[test]
+    fn replay_missing_file() {
+        assert!(matches!(
+            new_replay_device(DeviceConfig::with_options("replay", "")),
+            Err(DeviceError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            new_replay_device(DeviceConfig::with_options(
+                "replay",
+                "file=/nonexistent/ups.rec"
+            )),
+            Err(DeviceError::Backend { .. })
+        ));
+    }
+
+    #[test]
+    fn replay_timing() {
+        let path = temp_path("replay_timing.rec");
+        let mut file = File::create(&path).unwrap();
+        for elapsed in [0, 200] {
+            let snapshot = Snapshot {
+                elapsed: Duration::from_millis(elapsed),
+                ..Default::default()
+            };
+            write_snapshot(&mut file, &snapshot).unwrap();
+        }
+
+        let mut replay = new_replay_device(DeviceConfig::with_options(
+            "replay",
+            &format!("file={};speed=2", path.display()),
+        ))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let clock = FakeClock::new();
+        replay.clock = clock.clone();
+
+        let start = clock.now();
+        assert!(replay.read().is_some());
+        assert!(replay.read().is_some());
+        assert!(replay.read().is_none());
+        /* 200 ms at twice the speed */
+        assert_eq!(clock.now() - start, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn replay_uses_host_limits() {
+        let path = temp_path("replay_uses_host_limits.rec");
+        let mut file = File::create(&path).unwrap();
+        let snapshot = Snapshot {
+            variables: BTreeMap::from([
+                ("battery.charge".into(), Some("40".into())),
+                ("battery.charge.low".into(), Some("20".into())),
+                ("ups.status".into(), Some("OB DISCHRG".into())),
+            ]),
+            ..Default::default()
+        };
+        write_snapshot(&mut file, &snapshot).unwrap();
+
+        let replay = new_replay_device(DeviceConfig::with_options(
+            "replay",
+            &format!("file={};speed=0", path.display()),
+        ))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let limit = decode::find(nut::UPS_REPORT_DESCRIPTOR, "RemainingCapacityLimit").unwrap();
+        replay.set_feature(limit.report_id, &[50]);
+        while replay.read().is_some() {}
+
+        let data = replay.data().read().unwrap();
+        let reading = |path| decode::lookup(&data, path).unwrap().reading;
+        assert_eq!(
+            reading("RemainingCapacityLimit"),
+            decode::Reading::Number(50.0)
+        );
+        assert_eq!(
+            reading("BelowRemainingCapacityLimit"),
+            decode::Reading::Flag(true)
+        );
+    }
+
+    #[test]
+    fn replay_grace_period() {
+        let path = temp_path("replay_grace_period.rec");
+        let mut file = File::create(&path).unwrap();
+        let online = Snapshot {
+            variables: BTreeMap::from([("ups.status".into(), Some("OL".into()))]),
+            ..Default::default()
+        };
+        write_snapshot(&mut file, &online).unwrap();
+        for elapsed in [5, 15] {
+            let failed = Snapshot {
+                elapsed: Duration::from_secs(elapsed),
+                error: Some("No NUT endpoint available".into()),
+                ..Default::default()
+            };
+            write_snapshot(&mut file, &failed).unwrap();
+        }
+
+        let replay = new_replay_device(DeviceConfig::with_options(
+            "replay",
+            &format!("file={};speed=0", path.display()),
+        ))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let status = || {
+            let data = replay.data().read().unwrap();
+            let reading = |path| decode::lookup(&data, path).unwrap().reading;
+            (reading("ACPresent"), reading("CommunicationLost"))
+        };
+        use decode::Reading::Flag;
+        replay.read().unwrap();
+        assert_eq!(status(), (Flag(true), Flag(false)));
+        /* the failed poll 5 seconds later is within the grace period */
+        replay.read().unwrap();
+        assert_eq!(status(), (Flag(true), Flag(false)));
+        replay.read().unwrap();
+        assert_eq!(status(), (Flag(false), Flag(true)));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let server = MockServer::start();
+        server.add_ups("ups", "mock ups");
+        server.set_var("ups", "battery.charge", "80");
+        server.set_var("ups", "ups.status", "OL");
+
+        let path = temp_path("record_and_replay.rec");
+        let path_str = path.to_str().unwrap();
+
+        let mut live_config =
+            DeviceConfig::with_options("nut", &format!("record={path_str};poll=0"));
+        live_config.endpoints = vec![server.endpoint()];
+        let live = nut::new_nut_device(live_config).unwrap();
+
+        let mut recorded = Vec::new();
+        let mut read = |count: usize| {
+            for _ in 0..count {
+                recorded.push(live.read().unwrap());
+            }
+        };
+
+        read(2);
+        server.set_var("ups", "battery.charge", "0");
+        server.set_var("ups", "ups.status", "OB DISCHRG");
+        read(2);
+        server.fail_var("ups", "battery.charge", Fault::error("DATA-STALE"));
+        read(1);
+        server.set_var("ups", "battery.charge", "60");
+        server.set_var("ups", "battery.charge.low", "20");
+        server.set_var("ups", "ups.status", "OL CHRG");
+        read(3);
+        drop(live);
+
+        let replay = new_replay_device(DeviceConfig::with_options(
+            "replay",
+            &format!("file={path_str};speed=0"),
+        ))
+        .unwrap();
+        let replayed = std::iter::from_fn(|| replay.read()).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replayed, recorded);
+    }
+}
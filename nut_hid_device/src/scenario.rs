@@ -0,0 +1,425 @@
+//! Simulation scenarios run by the dummy backend.
+//!
+//! A scenario is a TOML timeline of events changing the simulated ups:
+//!
+//! ```toml
+//! step = 10      # seconds of simulated time between reports
+//! end = 900      # the scenario ends after that many seconds, runs forever if not set
+//! charge = 100   # charge at the start in percent
+//! runtime = 30   # minutes of runtime at full charge
+//!
+//! [[event]]
+//! at = 10
+//! ac = false
+//! rate = -18     # change of the charge in percent per minute
+//!
+//! [[event]]
+//! at = 300
+//! status = { below_remaining_capacity_limit = true }
+//! ```
+//!
+//! AC presence, charging, discharging and fully charged or discharged follow the
+//! simulated power and charge. `status` forces bits on or off until a later event
+//! changes them again, the bits are named as in mapping files.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::*;
+use dummy::{REPORT_ID_REMAININGCAPACITY, runtime_report};
+use mapping::Usage;
+use nut::PresentStatus;
+use status::StatusBit;
+
+/// Scenarios shipped with the backend, selected by name
+pub const SCENARIOS: &[(&str, &str)] = &[
+    ("power_loss", include_str!("../scenarios/power_loss.toml")),
+    (
+        "short_outage",
+        include_str!("../scenarios/short_outage.toml"),
+    ),
+    (
+        "forced_shutdown",
+        include_str!("../scenarios/forced_shutdown.toml"),
+    ),
+    (
+        "replace_battery",
+        include_str!("../scenarios/replace_battery.toml"),
+    ),
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventSection {
+    at: f64,
+    ac: Option<bool>,
+    charge: Option<f64>,
+    rate: Option<f64>,
+    #[serde(default)]
+    status: BTreeMap<String, bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioFile {
+    step: f64,
+    end: Option<f64>,
+    #[serde(default = "full")]
+    charge: f64,
+    runtime: f64,
+    #[serde(default)]
+    event: Vec<EventSection>,
+}
+
+fn full() -> f64 {
+    100.0
+}
+
+/// A change of the simulated ups
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    /// Time since the start of the scenario
+    pub at: Duration,
+    pub ac: Option<bool>,
+    /// Charge in percent
+    pub charge: Option<f64>,
+    /// Change of the charge in percent per minute
+    pub rate: Option<f64>,
+    pub status: BTreeMap<StatusBit, bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub step: Duration,
+    pub end: Option<Duration>,
+    pub charge: f64,
+    /// Minutes of runtime at full charge
+    pub runtime: f64,
+    /// Events ordered by time
+    pub events: Vec<Event>,
+}
+
+fn seconds(name: &str, value: f64) -> Result<Duration, ConfigError> {
+    Duration::try_from_secs_f64(value)
+        .map_err(|_| ConfigError::InvalidScenario(format!("invalid {name} {value}")))
+}
+
+fn percent(value: f64) -> Result<f64, ConfigError> {
+    match value {
+        0.0..=100.0 => Ok(value),
+        _ => Err(ConfigError::InvalidScenario(format!(
+            "charge {value} outside of 0 to 100"
+        ))),
+    }
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Scenario, ConfigError> {
+        let file: ScenarioFile =
+            toml::from_str(text).map_err(|err| ConfigError::InvalidScenario(err.to_string()))?;
+
+        let step = seconds("step", file.step)?;
+        if step.is_zero() {
+            return Err(ConfigError::InvalidScenario("step must not be 0".into()));
+        }
+        if file.runtime < 0.0 {
+            return Err(ConfigError::InvalidScenario(format!(
+                "invalid runtime {}",
+                file.runtime
+            )));
+        }
+
+        let mut events = Vec::new();
+        for section in file.event {
+            let mut status = BTreeMap::new();
+            for (name, set) in section.status {
+                let bit = StatusBit::from_name(&name).ok_or_else(|| {
+                    ConfigError::InvalidScenario(format!("unknown status bit '{name}'"))
+                })?;
+                status.insert(bit, set);
+            }
+            events.push(Event {
+                at: seconds("time", section.at)?,
+                ac: section.ac,
+                charge: section.charge.map(percent).transpose()?,
+                rate: section.rate,
+                status,
+            });
+        }
+        events.sort_by_key(|event| event.at);
+
+        Ok(Scenario {
+            step,
+            end: file.end.map(|end| seconds("end", end)).transpose()?,
+            charge: percent(file.charge)?,
+            runtime: file.runtime,
+            events,
+        })
+    }
+
+    /// A shipped scenario by name, otherwise the scenario file at the path
+    pub fn load(name: &str) -> Result<Scenario, ConfigError> {
+        if let Some((_, text)) = SCENARIOS.iter().find(|(scenario, _)| *scenario == name) {
+            return Scenario::parse(text);
+        }
+        let text = fs::read_to_string(name)
+            .map_err(|err| ConfigError::InvalidScenario(format!("{name}: {err}")))?;
+        Scenario::parse(&text)
+    }
+}
+
+/// State of the simulated ups while a scenario runs
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    scenario: Scenario,
+    /// Simulated time, `None` before the first step
+    elapsed: Option<Duration>,
+    next_event: usize,
+    ac: bool,
+    charge: f64,
+    rate: f64,
+    status: BTreeMap<StatusBit, bool>,
+}
+
+impl Simulation {
+    pub fn new(scenario: Scenario) -> Simulation {
+        Simulation {
+            charge: scenario.charge,
+            scenario,
+            elapsed: None,
+            next_event: 0,
+            ac: true,
+            rate: 0.0,
+            status: BTreeMap::new(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+
+    pub fn step_duration(&self) -> Duration {
+        self.scenario.step
+    }
+
+    pub fn charge(&self) -> f64 {
+        self.charge
+    }
+
+    /// Advance by one step and apply the events due, `None` once the scenario ended
+    pub fn step(&mut self) -> Option<Vec<(u8, Vec<u8>)>> {
+        let elapsed = self
+            .elapsed
+            .map_or(Duration::ZERO, |elapsed| elapsed + self.scenario.step);
+        if self.scenario.end.is_some_and(|end| elapsed > end) {
+            return None;
+        }
+        if self.elapsed.is_some() {
+            let minutes = self.scenario.step.as_secs_f64() / 60.0;
+            self.charge = (self.charge + self.rate * minutes).clamp(0.0, 100.0);
+        }
+        self.elapsed = Some(elapsed);
+
+        while let Some(event) = self.scenario.events.get(self.next_event)
+            && event.at <= elapsed
+        {
+            if let Some(ac) = event.ac {
+                self.ac = ac;
+            }
+            if let Some(charge) = event.charge {
+                self.charge = charge;
+            }
+            if let Some(rate) = event.rate {
+                self.rate = rate;
+            }
+            self.status.extend(&event.status);
+            self.next_event += 1;
+        }
+
+        Some(self.reports())
+    }
+
+    pub fn bits(&self) -> BTreeSet<StatusBit> {
+        let mut bits = BTreeSet::from([StatusBit::BatteryPresent]);
+        if self.ac {
+            bits.insert(StatusBit::AcPresent);
+            if self.charge < 100.0 && self.rate > 0.0 {
+                bits.insert(StatusBit::Charging);
+            }
+        } else {
+            bits.insert(StatusBit::Discharging);
+        }
+        if self.charge >= 100.0 {
+            bits.insert(StatusBit::FullyCharged);
+        }
+        if self.charge <= 0.0 {
+            bits.insert(StatusBit::FullyDischarged);
+        }
+
+        for (bit, set) in &self.status {
+            if *set {
+                bits.insert(*bit);
+            } else {
+                bits.remove(bit);
+            }
+        }
+        bits
+    }
+
+    fn reports(&self) -> Vec<(u8, Vec<u8>)> {
+        let minutes = self.charge / 100.0 * self.scenario.runtime;
+        vec![
+            (
+                REPORT_ID_REMAININGCAPACITY,
+                Usage::RemainingCapacity.encode(self.charge),
+            ),
+            runtime_report(minutes * 60.0),
+            PresentStatus::from_bits(&self.bits()).report(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str) -> Vec<(Duration, f64, BTreeSet<StatusBit>)> {
+        let mut simulation = Simulation::new(Scenario::load(name).unwrap());
+        let mut states = Vec::new();
+        while simulation.step().is_some() {
+            states.push((
+                simulation.elapsed().unwrap(),
+                simulation.charge(),
+                simulation.bits(),
+            ));
+        }
+        states
+    }
+
+    /* charge as reported, rounded to percent */
+    fn at(
+        states: &[(Duration, f64, BTreeSet<StatusBit>)],
+        seconds: u64,
+    ) -> (f64, &BTreeSet<StatusBit>) {
+        let (_, charge, bits) = states
+            .iter()
+            .find(|(elapsed, _, _)| *elapsed == Duration::from_secs(seconds))
+            .unwrap();
+        (charge.round(), bits)
+    }
+
+    #[test]
+    fn shipped_scenarios_end() {
+        for (name, _) in SCENARIOS {
+            let scenario = Scenario::load(name).unwrap();
+            let end = scenario.end.unwrap();
+            let states = run(name);
+            assert_eq!(states.last().unwrap().0, end, "{name}");
+        }
+    }
+
+    #[test]
+    fn power_loss() {
+        use StatusBit::*;
+
+        let states = run("power_loss");
+        assert_eq!(
+            at(&states, 0),
+            (
+                100.0,
+                &BTreeSet::from([AcPresent, BatteryPresent, FullyCharged])
+            )
+        );
+        assert_eq!(
+            at(&states, 10).1,
+            &BTreeSet::from([BatteryPresent, Discharging, FullyCharged])
+        );
+        assert_eq!(
+            at(&states, 70),
+            (82.0, &BTreeSet::from([BatteryPresent, Discharging]))
+        );
+        assert!(at(&states, 300).1.contains(&BelowRemainingCapacityLimit));
+        assert!(!at(&states, 310).1.contains(&ShutdownImminent));
+        assert!(at(&states, 320).1.contains(&ShutdownImminent));
+        assert!(at(&states, 400).1.contains(&FullyDischarged));
+        assert_eq!(
+            at(&states, 610),
+            (2.0, &BTreeSet::from([AcPresent, BatteryPresent, Charging]))
+        );
+    }
+
+    #[test]
+    fn scenario_reports() {
+        let mut simulation = Simulation::new(Scenario::load("short_outage").unwrap());
+        for _ in 0..=12 {
+            simulation.step();
+        }
+        let reports = simulation.step().unwrap();
+
+        let descriptor = dummy::UPS_REPORT_DESCRIPTOR;
+        let values = reports
+            .iter()
+            .flat_map(|(id, report)| decode::decode(descriptor, ReportType::Input, *id, report))
+            .filter(|value| value.reading != decode::Reading::Flag(false))
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>();
+        /* 35 seconds after the outage at 5 percent per minute */
+        assert_eq!(
+            values,
+            [
+                "RemainingCapacity = 97 %",
+                "RunTimeToEmpty = 2329 s",
+                "PresentStatus.Discharging = true",
+                "PresentStatus.BatteryPresent = true",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in [
+            "runtime = 10\n",
+            "step = 0\nruntime = 10\n",
+            "step = 1\nruntime = -1\n",
+            "step = 1\nruntime = 10\ncharge = 120\n",
+            "step = 1\nruntime = 10\n[[event]]\nat = -1\n",
+            "step = 1\nruntime = 10\n[[event]]\nat = 1\nac = \"off\"\n",
+            "step = 1\nruntime = 10\n[[event]]\nat = 1\nstatus = { low_battery = true }\n",
+            "step = 1\nruntime = 10\nspeed = 2\n",
+        ] {
+            assert!(
+                matches!(Scenario::parse(text), Err(ConfigError::InvalidScenario(_))),
+                "{text}"
+            );
+        }
+        assert!(matches!(
+            Scenario::load("/nonexistent/scenario.toml"),
+            Err(ConfigError::InvalidScenario(_))
+        ));
+    }
+
+    #[test]
+    fn events_in_order() {
+        let scenario = Scenario::parse(
+            "step = 1\nruntime = 10\n[[event]]\nat = 5\nac = true\n\
+             [[event]]\nat = 2\nac = false\nstatus = { \"PresentStatus.Overload\" = true }\n",
+        )
+        .unwrap();
+        assert_eq!(
+            scenario
+                .events
+                .iter()
+                .map(|event| event.at.as_secs())
+                .collect::<Vec<_>>(),
+            [2, 5]
+        );
+        assert_eq!(
+            scenario.events[0].status,
+            BTreeMap::from([(StatusBit::Overload, true)])
+        );
+        assert_eq!(scenario.end, None);
+    }
+}
@@ -0,0 +1,677 @@
+//! Backend simulating the physics of a lead acid ups battery.
+//!
+//! The battery stores `capacity` Wh. On battery the load is drawn through an inverter
+//! of `efficiency`, and the Peukert effect drains the battery faster than the power
+//! drawn: relative to the rate emptying it in 20 hours, a load `n` times higher drains
+//! it `n^peukert` times faster. On line power the charger stores `charge_power` W
+//! times `charge_efficiency` up to `taper` percent, then the charge current falls
+//! linearly towards full charge.
+//!
+//! AC power is lost `outage_at` seconds after the start, for `outage_for` seconds or
+//! for good. The device uses the descriptor and report ids of the dummy backend.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::*;
+use clock::{Clock, SystemClock};
+use dummy::{
+    REPORT_ID_AVERAGETIME2FULL, REPORT_ID_REMAININGCAPACITY, REPORT_ID_REMNCAPACITYLIMIT,
+    REPORT_ID_VOLTAGE, new_dummy_device_data, runtime_report,
+};
+use log::info;
+use mapping::Usage;
+use nut::PresentStatus;
+use status::StatusBit;
+
+/// Hours in which the rated load empties the battery
+const RATED_HOURS: f64 = 20.0;
+/// Charge current at full charge relative to the charger power
+const MIN_TAPER: f64 = 0.05;
+/// Longest step the simulation is integrated with
+const INTEGRATION_STEP: Duration = Duration::from_secs(1);
+/// Time to full reported while not charging
+const NOT_CHARGING: u16 = u16::MAX;
+
+/// Battery and load of the simulated ups
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Model {
+    /// Energy stored when full in Wh
+    pub capacity: f64,
+    /// Load in W
+    pub load: f64,
+    /// Efficiency of the inverter on battery
+    pub efficiency: f64,
+    /// Share of the charger power stored in the battery
+    pub charge_efficiency: f64,
+    /// Peukert exponent, 1 for an ideal battery
+    pub peukert: f64,
+    /// Power of the charger in W
+    pub charge_power: f64,
+    /// Charge in percent above which the charger tapers off
+    pub taper: f64,
+    /// Nominal battery voltage
+    pub voltage: f64,
+    /// Charge in percent below which the battery is low
+    pub low: f64,
+}
+
+impl Default for Model {
+    fn default() -> Model {
+        Model {
+            capacity: 500.0,
+            load: 100.0,
+            efficiency: 0.9,
+            charge_efficiency: 0.85,
+            peukert: 1.2,
+            charge_power: 50.0,
+            taper: 80.0,
+            voltage: 12.0,
+            low: 20.0,
+        }
+    }
+}
+
+impl Model {
+    /// Power in W taken from the stored energy while on battery
+    pub fn drain(&self) -> f64 {
+        let rated = self.capacity / RATED_HOURS;
+        let power = self.load / self.efficiency;
+        rated * (power / rated).powf(self.peukert)
+    }
+
+    /// Power in W stored in the battery while charging at a charge in percent
+    pub fn charging(&self, charge: f64) -> f64 {
+        if charge >= 100.0 {
+            return 0.0;
+        }
+        let taper = ((100.0 - charge) / (100.0 - self.taper)).clamp(MIN_TAPER, 1.0);
+        self.charge_power * self.charge_efficiency * taper
+    }
+
+    /// Time until full from a charge in percent
+    pub fn time_to_full(&self, charge: f64) -> Duration {
+        /* percent per hour at the full charger power */
+        let rate = self.charge_power * self.charge_efficiency / self.capacity * 100.0;
+        let span = 100.0 - self.taper;
+        /* charge above which the current stays at its minimum */
+        let floor = 100.0 - MIN_TAPER * span;
+        let charge = charge.min(100.0);
+
+        let mut hours = (self.taper - charge).max(0.0) / rate;
+        /* the charge approaches full exponentially while the current falls linearly */
+        let start = charge.clamp(self.taper, floor);
+        hours += span / rate * ((100.0 - start) / (100.0 - floor)).ln();
+        hours += (100.0 - charge.max(floor)) / (rate * MIN_TAPER);
+        Duration::from_secs_f64(hours * 3600.0)
+    }
+}
+
+/// Periods without AC power
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Outage {
+    pub at: Option<Duration>,
+    /// Forever if not set
+    pub duration: Option<Duration>,
+}
+
+impl Outage {
+    pub fn ac_present(&self, elapsed: Duration) -> bool {
+        match (self.at, self.duration) {
+            (None, _) => true,
+            (Some(at), None) => elapsed < at,
+            (Some(at), Some(duration)) => elapsed < at || elapsed >= at + duration,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub model: Model,
+    pub outage: Outage,
+    /// Stored energy in Wh
+    energy: f64,
+    elapsed: Duration,
+}
+
+impl Simulation {
+    pub fn new(model: Model, outage: Outage, charge: f64) -> Simulation {
+        Simulation {
+            model,
+            outage,
+            energy: model.capacity * charge / 100.0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn ac_present(&self) -> bool {
+        self.outage.ac_present(self.elapsed)
+    }
+
+    /// Charge in percent
+    pub fn charge(&self) -> f64 {
+        self.energy / self.model.capacity * 100.0
+    }
+
+    pub fn advance(&mut self, duration: Duration) {
+        let mut remaining = duration;
+        while !remaining.is_zero() {
+            let step = remaining.min(INTEGRATION_STEP);
+            let power = match self.ac_present() {
+                true => self.model.charging(self.charge()),
+                false => -self.model.drain(),
+            };
+            self.energy =
+                (self.energy + power * step.as_secs_f64() / 3600.0).clamp(0.0, self.model.capacity);
+            self.elapsed += step;
+            remaining -= step;
+        }
+    }
+
+    /// Seconds until empty at the current load
+    pub fn runtime(&self) -> f64 {
+        self.energy / self.model.drain() * 3600.0
+    }
+
+    pub fn voltage(&self) -> f64 {
+        let nominal = self.model.voltage;
+        match (self.ac_present(), self.charge() >= 100.0) {
+            /* float and absorption charge */
+            (true, true) => nominal * 1.125,
+            (true, false) => nominal * 1.15,
+            /* open circuit voltage less the sag under load */
+            (false, _) => nominal * (0.96 + 0.001 * self.charge()),
+        }
+    }
+
+    pub fn bits(&self) -> BTreeSet<StatusBit> {
+        let charge = self.charge();
+        let mut bits = BTreeSet::from([StatusBit::BatteryPresent]);
+        if self.ac_present() {
+            bits.insert(StatusBit::AcPresent);
+            if charge < 100.0 {
+                bits.insert(StatusBit::Charging);
+            }
+        } else {
+            bits.insert(StatusBit::Discharging);
+        }
+        if charge >= 100.0 {
+            bits.insert(StatusBit::FullyCharged);
+        }
+        if charge < self.model.low {
+            bits.insert(StatusBit::BelowRemainingCapacityLimit);
+        }
+        if self.energy <= 0.0 {
+            bits.insert(StatusBit::FullyDischarged);
+        }
+        bits
+    }
+
+    /// Input reports of the current state
+    pub fn reports(&self) -> Vec<(u8, Vec<u8>)> {
+        let centivolts = (self.voltage() * 100.0).round() as u16;
+        vec![
+            (
+                REPORT_ID_REMAININGCAPACITY,
+                Usage::RemainingCapacity.encode(self.charge()),
+            ),
+            runtime_report(self.runtime()),
+            (REPORT_ID_VOLTAGE, centivolts.to_le_bytes().into()),
+            PresentStatus::from_bits(&self.bits()).report(),
+        ]
+    }
+
+    /// Seconds until full, only reported in a feature report
+    pub fn time_to_full(&self) -> u16 {
+        if !self.ac_present() {
+            return NOT_CHARGING;
+        }
+        let seconds = self.model.time_to_full(self.charge()).as_secs_f64();
+        seconds.round().min(f64::from(NOT_CHARGING - 1)) as u16
+    }
+}
+
+struct SimState {
+    pending: VecDeque<(u8, Vec<u8>)>,
+    simulation: Simulation,
+    /// Time of the last update, `None` before the first one
+    updated: Option<Instant>,
+}
+
+pub struct SimDevice {
+    device: RwLock<DeviceData>,
+    state: Mutex<SimState>,
+    clock: Arc<dyn Clock>,
+    poll_interval: Duration,
+}
+
+impl SimDevice {
+    fn store(device: &mut DeviceData, simulation: &Simulation) {
+        device.reports.set(
+            ReportType::Feature,
+            REPORT_ID_AVERAGETIME2FULL,
+            simulation.time_to_full().to_le_bytes(),
+        );
+    }
+}
+
+impl Device for SimDevice {
+    fn data(&self) -> &RwLock<DeviceData> {
+        &self.device
+    }
+
+    fn read(&self) -> Option<(u8, Vec<u8>)> {
+        /* get all pending */
+        let mut state = self.state.lock().unwrap();
+        if let Some(report) = state.pending.pop_front() {
+            return Some(report);
+        }
+
+        self.clock.sleep(self.poll_interval);
+
+        let now = self.clock.now();
+        if let Some(updated) = state.updated {
+            state
+                .simulation
+                .advance(now.saturating_duration_since(updated));
+        }
+        state.updated = Some(now);
+
+        let state = &mut *state;
+        let mut device = self.device.write().unwrap();
+        SimDevice::store(&mut device, &state.simulation);
+        device.publish(&mut state.pending, state.simulation.reports());
+        state.pending.pop_front()
+    }
+}
+
+const fn number(
+    name: &'static str,
+    default: &'static str,
+    description: &'static str,
+) -> OptionSchema {
+    OptionSchema {
+        name,
+        kind: OptionKind::Number,
+        required: false,
+        default: Some(default),
+        description,
+    }
+}
+
+pub const BACKEND: Backend = Backend {
+    name: "sim",
+    description: "Simulated UPS battery charging and discharging over time",
+    schema: ConfigSchema {
+        endpoints: false,
+        options: &[
+            number("capacity", "500", "Energy stored when full in Wh"),
+            number("load", "100", "Load in W"),
+            number("efficiency", "0.9", "Efficiency of the inverter, up to 1"),
+            number(
+                "charge_efficiency",
+                "0.85",
+                "Share of the charger power stored, up to 1",
+            ),
+            number("peukert", "1.2", "Peukert exponent, at least 1"),
+            number("charge_power", "50", "Power of the charger in W"),
+            number(
+                "taper",
+                "80",
+                "Charge in percent above which charging slows",
+            ),
+            number("voltage", "12", "Nominal battery voltage"),
+            number("charge", "100", "Charge in percent at the start"),
+            number(
+                "low",
+                "20",
+                "Charge in percent below which the battery is low",
+            ),
+            OptionSchema {
+                name: "outage_at",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: None,
+                description: "Seconds after the start at which AC power is lost",
+            },
+            OptionSchema {
+                name: "outage_for",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: None,
+                description: "Seconds until AC power returns, never if not set",
+            },
+            OptionSchema {
+                name: "poll",
+                kind: OptionKind::Seconds,
+                required: false,
+                default: Some("10"),
+                description: "Seconds between updates",
+            },
+        ],
+    },
+    factory: |config| Ok(Box::new(new_sim_device(config)?)),
+};
+
+fn option(
+    config: &DeviceConfig,
+    name: &str,
+    default: f64,
+    valid: impl Fn(f64) -> bool,
+    errors: &mut Vec<ConfigError>,
+) -> f64 {
+    let Some(value) = config.option(name) else {
+        return default;
+    };
+    match value.parse() {
+        Ok(number) if valid(number) => number,
+        _ => {
+            errors.push(ConfigError::InvalidOption {
+                name: name.into(),
+                value: value.into(),
+            });
+            default
+        }
+    }
+}
+
+pub fn new_sim_device(device_config: DeviceConfig) -> Result<SimDevice, DeviceError> {
+    info!("Creating Sim backend");
+
+    let defaults = Model::default();
+    let positive = |value: f64| value > 0.0;
+    let fraction = |value: f64| value > 0.0 && value <= 1.0;
+    let percent = |value: f64| (0.0..=100.0).contains(&value);
+
+    let mut errors = Vec::new();
+    let config = &device_config;
+    let model = Model {
+        capacity: option(config, "capacity", defaults.capacity, positive, &mut errors),
+        load: option(config, "load", defaults.load, positive, &mut errors),
+        efficiency: option(
+            config,
+            "efficiency",
+            defaults.efficiency,
+            fraction,
+            &mut errors,
+        ),
+        charge_efficiency: option(
+            config,
+            "charge_efficiency",
+            defaults.charge_efficiency,
+            fraction,
+            &mut errors,
+        ),
+        peukert: option(
+            config,
+            "peukert",
+            defaults.peukert,
+            |value| value >= 1.0,
+            &mut errors,
+        ),
+        charge_power: option(
+            config,
+            "charge_power",
+            defaults.charge_power,
+            positive,
+            &mut errors,
+        ),
+        taper: option(
+            config,
+            "taper",
+            defaults.taper,
+            |value| (0.0..100.0).contains(&value),
+            &mut errors,
+        ),
+        voltage: option(config, "voltage", defaults.voltage, positive, &mut errors),
+        low: option(config, "low", defaults.low, percent, &mut errors),
+    };
+    let charge = option(config, "charge", 100.0, percent, &mut errors);
+    if !errors.is_empty() {
+        return Err(DeviceError::InvalidConfig(errors));
+    }
+
+    let outage = Outage {
+        at: config.option("outage_at").and_then(parse_seconds),
+        duration: config.option("outage_for").and_then(parse_seconds),
+    };
+    let simulation = Simulation::new(model, outage, charge);
+
+    let mut device = new_dummy_device_data();
+    device.reports.set(
+        ReportType::Feature,
+        REPORT_ID_REMNCAPACITYLIMIT,
+        Usage::RemainingCapacityLimit.encode(model.low),
+    );
+    for (report_id, report) in simulation.reports() {
+        device.reports.set_value(report_id, report);
+    }
+    SimDevice::store(&mut device, &simulation);
+
+    Ok(SimDevice {
+        device: RwLock::new(device),
+        state: Mutex::new(SimState {
+            pending: VecDeque::new(),
+            simulation,
+            updated: None,
+        }),
+        clock: Arc::new(SystemClock),
+        poll_interval: config
+            .option("poll")
+            .and_then(parse_seconds)
+            .unwrap_or(Duration::from_secs(10)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::FakeClock;
+
+    fn ideal() -> Model {
+        Model {
+            capacity: 500.0,
+            load: 90.0,
+            efficiency: 0.9,
+            peukert: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn sim_device(options: &str) -> (SimDevice, Arc<FakeClock>) {
+        let clock = FakeClock::new();
+        let mut device = new_sim_device(DeviceConfig::with_options(
+            "sim",
+            &format!("poll=0;{options}"),
+        ))
+        .unwrap();
+        device.clock = clock.clone();
+        (device, clock)
+    }
+
+    /// Values of the reports of one update, by name
+    fn poll(device: &SimDevice) -> Vec<String> {
+        let mut values = Vec::new();
+        for _ in 0..4 {
+            let (report_id, report) = device.read().unwrap();
+            values.extend(
+                decode::decode(
+                    dummy::UPS_REPORT_DESCRIPTOR,
+                    ReportType::Input,
+                    report_id,
+                    &report,
+                )
+                .into_iter()
+                .filter(|value| value.reading != decode::Reading::Flag(false))
+                .map(|value| value.to_string()),
+            );
+        }
+        values
+    }
+
+    #[test]
+    fn peukert_effect() {
+        let model = ideal();
+        assert_eq!(model.drain(), 100.0);
+
+        /* 4 times the rated load of 25 W drains 4^1.2 times faster */
+        let model = Model {
+            peukert: 1.2,
+            ..ideal()
+        };
+        assert!((model.drain() - 25.0 * 4f64.powf(1.2)).abs() < 1e-9);
+
+        let light = Model { load: 9.0, ..model };
+        assert!(light.drain() < 10.0);
+    }
+
+    #[test]
+    fn discharge() {
+        let mut simulation = Simulation::new(
+            ideal(),
+            Outage {
+                at: Some(Duration::ZERO),
+                duration: None,
+            },
+            100.0,
+        );
+        assert_eq!(simulation.runtime(), 5.0 * 3600.0);
+
+        simulation.advance(Duration::from_secs(30 * 60));
+        assert!((simulation.charge() - 90.0).abs() < 1e-9);
+        assert!((simulation.runtime() - 4.5 * 3600.0).abs() < 1e-6);
+        assert_eq!(simulation.time_to_full(), NOT_CHARGING);
+
+        simulation.advance(Duration::from_secs(5 * 3600));
+        assert_eq!(simulation.charge(), 0.0);
+        assert_eq!(
+            simulation.bits(),
+            BTreeSet::from([
+                StatusBit::BatteryPresent,
+                StatusBit::Discharging,
+                StatusBit::BelowRemainingCapacityLimit,
+                StatusBit::FullyDischarged,
+            ])
+        );
+    }
+
+    #[test]
+    fn charger_taper() {
+        let model = ideal();
+        /* 50 W at 85 percent below the taper, falling linearly above it */
+        assert_eq!(model.charging(50.0), 42.5);
+        assert_eq!(model.charging(90.0), 21.25);
+        assert_eq!(model.charging(100.0), 0.0);
+        assert_eq!(model.charging(99.9), 42.5 * MIN_TAPER);
+
+        /* 150 Wh at 42.5 W up to the taper, which takes longer */
+        let constant = model.time_to_full(50.0) - model.time_to_full(80.0);
+        assert!((constant.as_secs_f64() - 150.0 / 42.5 * 3600.0).abs() < 1.0);
+        assert!(model.time_to_full(80.0) > Duration::from_secs_f64(100.0 / 42.5 * 3600.0));
+        assert_eq!(model.time_to_full(100.0), Duration::ZERO);
+
+        let mut simulation = Simulation::new(model, Outage::default(), 50.0);
+        let expected = simulation.time_to_full();
+        simulation.advance(Duration::from_secs(u64::from(expected) - 60));
+        assert!(simulation.charge() < 100.0);
+        assert!(simulation.charge() > 99.0);
+        simulation.advance(Duration::from_secs(120));
+        assert_eq!(simulation.charge(), 100.0);
+        assert!(simulation.bits().contains(&StatusBit::FullyCharged));
+    }
+
+    #[test]
+    fn outage() {
+        let outage = Outage {
+            at: Some(Duration::from_secs(60)),
+            duration: Some(Duration::from_secs(120)),
+        };
+        assert!(outage.ac_present(Duration::from_secs(59)));
+        assert!(!outage.ac_present(Duration::from_secs(60)));
+        assert!(!outage.ac_present(Duration::from_secs(179)));
+        assert!(outage.ac_present(Duration::from_secs(180)));
+        assert!(Outage::default().ac_present(Duration::MAX));
+    }
+
+    #[test]
+    fn poll_interval() {
+        let (device, clock) = sim_device("poll=60");
+        let start = clock.now();
+        poll(&device);
+        poll(&device);
+        assert_eq!(clock.now() - start, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn reports_over_time() {
+        let (device, clock) = sim_device("peukert=1;load=90;outage_at=60;outage_for=1800");
+
+        assert_eq!(
+            poll(&device),
+            [
+                "RemainingCapacity = 100 %",
+                "RunTimeToEmpty = 18000 s",
+                "Voltage = 13.5 V",
+                "PresentStatus.ACPresent = true",
+                "PresentStatus.BatteryPresent = true",
+                "PresentStatus.FullyCharged = true",
+            ]
+        );
+
+        /* 30 minutes on battery use up a tenth of the charge, the last second is still to go */
+        clock.advance(Duration::from_secs(60 + 30 * 60 - 1));
+        assert_eq!(
+            poll(&device),
+            [
+                "RemainingCapacity = 90 %",
+                "RunTimeToEmpty = 16201 s",
+                "Voltage = 12.6 V",
+                "PresentStatus.Discharging = true",
+                "PresentStatus.BatteryPresent = true",
+            ]
+        );
+        let data = device.data().read().unwrap();
+        assert_eq!(
+            data.reports
+                .get(ReportType::Feature, REPORT_ID_AVERAGETIME2FULL),
+            Some(NOT_CHARGING.to_le_bytes().as_slice())
+        );
+        drop(data);
+
+        clock.advance(Duration::from_secs(1));
+        let values = poll(&device);
+        assert_eq!(values[2], "Voltage = 13.8 V");
+        assert!(values.contains(&"PresentStatus.Charging = true".to_string()));
+
+        /* the feature reports follow the input reports */
+        let data = device.data().read().unwrap();
+        assert_eq!(
+            data.reports
+                .get(ReportType::Feature, REPORT_ID_REMAININGCAPACITY),
+            Some([90].as_slice())
+        );
+        /* 8.5 percent per hour tapering off from 90 to 99 percent over 20/8.5 ln(10) hours,
+        then the last percent at the minimum current in 1/0.425 hours */
+        let time_to_full = decode::decode(
+            dummy::UPS_REPORT_DESCRIPTOR,
+            ReportType::Feature,
+            REPORT_ID_AVERAGETIME2FULL,
+            data.reports
+                .get(ReportType::Feature, REPORT_ID_AVERAGETIME2FULL)
+                .unwrap(),
+        );
+        assert_eq!(time_to_full[0].to_string(), "AverageTimeToFull = 27975 s");
+    }
+
+    #[test]
+    fn invalid_options() {
+        let Err(DeviceError::InvalidConfig(errors)) = new_sim_device(DeviceConfig::with_options(
+            "sim",
+            "efficiency=1.5;peukert=0.5;charge=x",
+        )) else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(errors.len(), 3);
+    }
+}
@@ -0,0 +1,604 @@
+//! Rules deriving the PresentStatus bits from NUT variables.
+//!
+//! Each bit is an expression over the tokens of `ups.status` and other variables:
+//!
+//! ```text
+//! OL & !BYPASS
+//! CHRG | battery_charger_status == "charging"
+//! LB | battery.charge < 10
+//! ```
+//!
+//! A bare word is true if `ups.status` contains it. A variable compared to a number is
+//! false if the variable is missing or not numeric, a missing variable compares equal
+//! to `""`. `!`, `&`, `|` and parentheses combine expressions, `&` binds tighter.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::de::value::Error as ValueError;
+
+use super::*;
+use descriptor::{PRESENT_STATUS, usage};
+use nut::Variables;
+use rups::ClientError;
+
+/// Bits of the PresentStatus report
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusBit {
+    Charging,
+    Discharging,
+    AcPresent,
+    BatteryPresent,
+    BelowRemainingCapacityLimit,
+    RemainingTimeLimitExpired,
+    NeedReplacement,
+    VoltageNotRegulated,
+    FullyCharged,
+    FullyDischarged,
+    ShutdownRequested,
+    ShutdownImminent,
+    CommunicationLost,
+    Overload,
+}
+
+impl StatusBit {
+    pub const ALL: [StatusBit; 14] = [
+        StatusBit::Charging,
+        StatusBit::Discharging,
+        StatusBit::AcPresent,
+        StatusBit::BatteryPresent,
+        StatusBit::BelowRemainingCapacityLimit,
+        StatusBit::RemainingTimeLimitExpired,
+        StatusBit::NeedReplacement,
+        StatusBit::VoltageNotRegulated,
+        StatusBit::FullyCharged,
+        StatusBit::FullyDischarged,
+        StatusBit::ShutdownRequested,
+        StatusBit::ShutdownImminent,
+        StatusBit::CommunicationLost,
+        StatusBit::Overload,
+    ];
+
+    /// Usage of the bit in the PresentStatus collection
+    pub fn usage(&self) -> u32 {
+        match self {
+            StatusBit::Charging => usage(0x85, 0x44),
+            StatusBit::Discharging => usage(0x85, 0x45),
+            StatusBit::AcPresent => usage(0x85, 0xD0),
+            StatusBit::BatteryPresent => usage(0x85, 0xD1),
+            StatusBit::BelowRemainingCapacityLimit => usage(0x85, 0x42),
+            StatusBit::RemainingTimeLimitExpired => usage(0x85, 0x43),
+            StatusBit::NeedReplacement => usage(0x85, 0x4B),
+            StatusBit::VoltageNotRegulated => usage(0x85, 0xDB),
+            StatusBit::FullyCharged => usage(0x85, 0x46),
+            StatusBit::FullyDischarged => usage(0x85, 0x47),
+            StatusBit::ShutdownRequested => usage(0x84, 0x68),
+            StatusBit::ShutdownImminent => usage(0x84, 0x69),
+            StatusBit::CommunicationLost => usage(0x84, 0x73),
+            StatusBit::Overload => usage(0x84, 0x65),
+        }
+    }
+
+    /// Bit by its snake case name or its path in the UPS descriptor, e.g.
+    /// `UPS.PowerSummary.PresentStatus.ACPresent`
+    pub fn from_name(name: &str) -> Option<StatusBit> {
+        if let Ok(bit) =
+            StatusBit::deserialize(IntoDeserializer::<ValueError>::into_deserializer(name))
+        {
+            return Some(bit);
+        }
+        let field = decode::find(nut::UPS_REPORT_DESCRIPTOR, name)?;
+        if field.collection != Some(PRESENT_STATUS) {
+            return None;
+        }
+        StatusBit::ALL
+            .into_iter()
+            .find(|bit| Some(bit.usage()) == field.usage)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(bool),
+    /// Token present in `ups.status`
+    Token(String),
+    Compare {
+        variable: String,
+        compare: Compare,
+        value: Value,
+    },
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// Variables read during a poll
+pub struct StatusValues {
+    tokens: HashSet<String>,
+    variables: BTreeMap<String, Option<String>>,
+}
+
+impl StatusValues {
+    pub fn new(variables: BTreeMap<String, Option<String>>) -> StatusValues {
+        let tokens = variables
+            .get(STATUS_VARIABLE)
+            .cloned()
+            .flatten()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        StatusValues { tokens, variables }
+    }
+
+    fn get(&self, variable: &str) -> Option<&str> {
+        self.variables.get(variable)?.as_deref()
+    }
+}
+
+const STATUS_VARIABLE: &str = "ups.status";
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {token}")),
+        }
+    }
+
+    pub fn evaluate(&self, values: &StatusValues) -> bool {
+        match self {
+            Expr::Const(value) => *value,
+            Expr::Token(token) => values.tokens.contains(token),
+            Expr::Compare {
+                variable,
+                compare,
+                value: Value::Text(text),
+            } => {
+                let equal = values.get(variable).unwrap_or_default() == text;
+                (*compare == Compare::Equal) == equal
+            }
+            Expr::Compare {
+                variable,
+                compare,
+                value: Value::Number(number),
+            } => {
+                let Some(value) = values
+                    .get(variable)
+                    .and_then(|value| value.trim().parse::<f64>().ok())
+                else {
+                    return false;
+                };
+                match compare {
+                    Compare::Equal => value == *number,
+                    Compare::NotEqual => value != *number,
+                    Compare::Less => value < *number,
+                    Compare::LessEqual => value <= *number,
+                    Compare::Greater => value > *number,
+                    Compare::GreaterEqual => value >= *number,
+                }
+            }
+            Expr::Not(expr) => !expr.evaluate(values),
+            Expr::And(left, right) => left.evaluate(values) && right.evaluate(values),
+            Expr::Or(left, right) => left.evaluate(values) || right.evaluate(values),
+        }
+    }
+
+    fn variables<'a>(&'a self, result: &mut BTreeSet<&'a str>) {
+        match self {
+            Expr::Const(_) | Expr::Token(_) => {}
+            Expr::Compare { variable, .. } => {
+                result.insert(variable);
+            }
+            Expr::Not(expr) => expr.variables(result),
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.variables(result);
+                right.variables(result);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Compare(Compare),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Text(text) => write!(f, "\"{text}\""),
+            Token::Compare(compare) => write!(f, "{compare:?}"),
+            Token::Not => write!(f, "'!'"),
+            Token::And => write!(f, "'&'"),
+            Token::Or => write!(f, "'|'"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+' | ':')
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut followed_by_equal = || chars.next_if_eq(&'=').is_some();
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' => Token::And,
+            '|' => Token::Or,
+            '!' if followed_by_equal() => Token::Compare(Compare::NotEqual),
+            '!' => Token::Not,
+            '=' if followed_by_equal() => Token::Compare(Compare::Equal),
+            '<' if followed_by_equal() => Token::Compare(Compare::LessEqual),
+            '<' => Token::Compare(Compare::Less),
+            '>' if followed_by_equal() => Token::Compare(Compare::GreaterEqual),
+            '>' => Token::Compare(Compare::Greater),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+                Token::Text(text)
+            }
+            c if is_word(c) => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| is_word(*c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected '{c}'")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(expr.into(), self.and()?.into());
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(expr.into(), self.unary()?.into());
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next().cloned() {
+            Some(Token::Not) => Ok(Expr::Not(self.unary()?.into())),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("missing ')'".into()),
+                }
+            }
+            Some(Token::Word(word)) => {
+                let Some(Token::Compare(compare)) = self.peek().cloned() else {
+                    return Ok(match word.as_str() {
+                        "true" => Expr::Const(true),
+                        "false" => Expr::Const(false),
+                        _ => Expr::Token(word),
+                    });
+                };
+                self.next();
+                let value = match self.next() {
+                    Some(Token::Text(text)) => {
+                        if !matches!(compare, Compare::Equal | Compare::NotEqual) {
+                            return Err(format!("cannot compare {word} to text with {compare:?}"));
+                        }
+                        Value::Text(text.clone())
+                    }
+                    Some(Token::Word(number)) => Value::Number(
+                        number
+                            .parse()
+                            .map_err(|_| format!("'{number}' is not a number"))?,
+                    ),
+                    _ => return Err(format!("missing value to compare {word} to")),
+                };
+                Ok(Expr::Compare {
+                    variable: word,
+                    compare,
+                    value,
+                })
+            }
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Err("unexpected end".into()),
+        }
+    }
+}
+
+/// Expression of every status bit, bits without one are never set
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusRules {
+    pub rules: BTreeMap<StatusBit, Expr>,
+}
+
+impl Default for StatusRules {
+    fn default() -> StatusRules {
+        let rules = [
+            (
+                StatusBit::Charging,
+                r#"CHRG | battery_charger_status == "charging""#,
+            ),
+            (
+                StatusBit::Discharging,
+                r#"DISCHRG | battery_charger_status == "discharging""#,
+            ),
+            (StatusBit::AcPresent, "OL"),
+            (StatusBit::Overload, "OVER"),
+            (StatusBit::FullyCharged, "HB"),
+            (StatusBit::BelowRemainingCapacityLimit, "LB"),
+            (
+                StatusBit::CommunicationLost,
+                r#"OFF | WAIT | ups.status == """#,
+            ),
+            (StatusBit::BatteryPresent, "!BYPASS"),
+            (StatusBit::NeedReplacement, "RB"),
+            /* forced shutdown by the primary or a running shutdown timer */
+            (StatusBit::ShutdownImminent, "FSD | ups.timer.shutdown == 0"),
+            (StatusBit::ShutdownRequested, "ups.timer.shutdown >= 0"),
+        ];
+        StatusRules {
+            rules: rules
+                .into_iter()
+                .map(|(bit, rule)| (bit, Expr::parse(rule).unwrap()))
+                .collect(),
+        }
+    }
+}
+
+impl StatusRules {
+    /// Replace the rules of the given bits
+    pub fn set(&mut self, rules: BTreeMap<StatusBit, String>) -> Result<(), ConfigError> {
+        for (bit, rule) in rules {
+            let expr = Expr::parse(&rule)
+                .map_err(|err| ConfigError::InvalidMapping(format!("{bit:?} '{rule}': {err}")))?;
+            self.rules.insert(bit, expr);
+        }
+        Ok(())
+    }
+
+    /// Variables the rules depend on, `ups.status` first
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables = BTreeSet::new();
+        for expr in self.rules.values() {
+            expr.variables(&mut variables);
+        }
+        variables.remove(STATUS_VARIABLE);
+
+        let mut result = vec![STATUS_VARIABLE];
+        result.extend(variables);
+        result
+    }
+
+    /// Read the variables and evaluate every rule, returning the bits set
+    pub fn evaluate(
+        &self,
+        variables: &mut dyn Variables,
+    ) -> Result<BTreeSet<StatusBit>, ClientError> {
+        let mut values = BTreeMap::new();
+        for variable in self.variables() {
+            values.insert(variable.to_string(), variables.get_str(variable)?);
+        }
+        let values = StatusValues::new(values);
+
+        Ok(self
+            .rules
+            .iter()
+            .filter(|(_, expr)| expr.evaluate(&values))
+            .map(|(bit, _)| *bit)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use replay::Snapshot;
+
+    fn snapshot(variables: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            variables: variables
+                .iter()
+                .map(|(name, value)| (name.to_string(), Some(value.to_string())))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn evaluate(rule: &str, variables: &[(&str, &str)]) -> bool {
+        let values = StatusValues::new(snapshot(variables).variables);
+        Expr::parse(rule).unwrap().evaluate(&values)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Expr::parse("OL").unwrap(), Expr::Token("OL".into()));
+        assert_eq!(
+            Expr::parse("!OB & (OL | true)").unwrap(),
+            Expr::And(
+                Expr::Not(Expr::Token("OB".into()).into()).into(),
+                Expr::Or(Expr::Token("OL".into()).into(), Expr::Const(true).into()).into()
+            )
+        );
+        assert_eq!(
+            Expr::parse("battery.charge<=-5.5").unwrap(),
+            Expr::Compare {
+                variable: "battery.charge".into(),
+                compare: Compare::LessEqual,
+                value: Value::Number(-5.5)
+            }
+        );
+
+        for rule in [
+            "",
+            "OL &",
+            "(OL",
+            "OL)",
+            "OL OB",
+            "battery.charge <",
+            "battery.charge < low",
+            "battery.charge < \"10\"",
+            "ups.status == \"OL",
+            "OL = OB",
+            "OL # comment",
+        ] {
+            assert!(Expr::parse(rule).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn evaluate_rules() {
+        let status = [("ups.status", "OL CHRG")];
+        assert!(evaluate("OL", &status));
+        assert!(!evaluate("OB", &status));
+        assert!(evaluate("OL & CHRG & !LB", &status));
+        assert!(evaluate("OB | CHRG & OL", &status));
+        assert!(!evaluate("(OB | CHRG) & LB", &status));
+        assert!(!evaluate("false", &status));
+
+        let variables = [
+            ("battery.charge", "15"),
+            ("battery.charger.status", "resting"),
+        ];
+        assert!(evaluate("battery.charge < 20", &variables));
+        assert!(evaluate("battery.charge >= 15", &variables));
+        assert!(!evaluate("battery.charge != 15", &variables));
+        assert!(!evaluate("battery.runtime < 120", &variables));
+        assert!(evaluate(
+            "battery.charger.status == \"resting\"",
+            &variables
+        ));
+        assert!(evaluate(
+            "battery.charger.status != \"charging\"",
+            &variables
+        ));
+        assert!(evaluate("ups.status == \"\"", &variables));
+        assert!(!evaluate("ups.status != \"\"", &variables));
+    }
+
+    #[test]
+    fn variables() {
+        let mut rules = StatusRules::default();
+        assert_eq!(
+            rules.variables(),
+            ["ups.status", "battery_charger_status", "ups.timer.shutdown"]
+        );
+
+        rules
+            .set(BTreeMap::from([(
+                StatusBit::BelowRemainingCapacityLimit,
+                "LB | battery.charge < 10".into(),
+            )]))
+            .unwrap();
+        assert_eq!(
+            rules.variables(),
+            [
+                "ups.status",
+                "battery.charge",
+                "battery_charger_status",
+                "ups.timer.shutdown"
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_rules() {
+        let mut rules = StatusRules::default();
+        rules
+            .set(BTreeMap::from([
+                (StatusBit::BatteryPresent, "true".into()),
+                (StatusBit::AcPresent, "OL | BYPASS".into()),
+                (StatusBit::ShutdownImminent, "battery.runtime < 60".into()),
+            ]))
+            .unwrap();
+
+        let bits = rules
+            .evaluate(&mut snapshot(&[
+                ("ups.status", "BYPASS"),
+                ("battery.runtime", "30"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            bits,
+            BTreeSet::from([
+                StatusBit::AcPresent,
+                StatusBit::BatteryPresent,
+                StatusBit::ShutdownImminent
+            ])
+        );
+
+        let err = rules.set(BTreeMap::from([(StatusBit::Overload, "OVER &".into())]));
+        assert!(matches!(err, Err(ConfigError::InvalidMapping(_))));
+    }
+}
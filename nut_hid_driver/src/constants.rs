@@ -1,12 +1,7 @@
 
-/* 53c0d411-cfb1-4d29-8f81-e705f3ac17a1 */
 pub const DEVPROP_NUTHID_GUID: wdk_sys::GUID = wdk_sys::GUID {
-    Data1: 0x53c0d411,
-    Data2: 0xcfb1,
-    Data3: 0x4d29,
-    Data4: [0x8f, 0x81, 0xe7, 0x05, 0xf3, 0xac, 0x17, 0xa1],
+    Data1: nut_hid_properties::DEVPROP_NUTHID_GUID.data1,
+    Data2: nut_hid_properties::DEVPROP_NUTHID_GUID.data2,
+    Data3: nut_hid_properties::DEVPROP_NUTHID_GUID.data3,
+    Data4: nut_hid_properties::DEVPROP_NUTHID_GUID.data4,
 };
-
-pub const DEVPROP_NUTHID_KEY_HOST: u32 = 2;
-pub const DEVPROP_NUTHID_KEY_PORT: u32 = 3;
-pub const DEVPROP_NUTHID_KEY_BACKEND: u32 = 4;
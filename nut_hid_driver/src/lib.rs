@@ -2,7 +2,10 @@ use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::{os::windows::ffi::OsStrExt, slice, string::String};
 
-use wdk_sys::STATUS_NOT_SUPPORTED;
+use wdk_sys::{
+    STATUS_ACCESS_DENIED, STATUS_DEVICE_CONFIGURATION_ERROR, STATUS_DEVICE_DATA_ERROR,
+    STATUS_NOT_SUPPORTED, STATUS_UNSUCCESSFUL,
+};
 use wdk_sys::{
     _HID_DESCRIPTOR__HID_DESCRIPTOR_DESC_LIST, _WDF_IO_QUEUE_DISPATCH_TYPE,
     _WDF_TRI_STATE::WdfUseDefault, HID_DESCRIPTOR, HID_DEVICE_ATTRIBUTES, NT_ERROR, NT_SUCCESS,
@@ -13,14 +16,13 @@ use wdk_sys::{
     WDFDEVICE_INIT, WDFDRIVER, WDFOBJECT, WDFQUEUE, WDFREQUEST, call_unsafe_wdf_function_binding,
 };
 
-mod backports;
 mod constants;
 mod hid;
 mod logger;
 mod wdf;
 
 use hid::*;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use nut_hid_device::*;
 use std::sync::mpsc::{Sender, channel};
 use wdf::*;
@@ -137,29 +139,25 @@ fn wdf_device_create(
 }
 
 fn get_device_config(device: WDFDEVICE) -> Result<DeviceConfig, NTSTATUS> {
-    let host = wdf_device_query_property_string(
-        device,
-        constants::DEVPROP_NUTHID_GUID,
-        constants::DEVPROP_NUTHID_KEY_HOST,
-    )?;
-
-    let port = wdf_device_query_property_u32(
-        device,
-        constants::DEVPROP_NUTHID_GUID,
-        constants::DEVPROP_NUTHID_KEY_PORT,
-    )?;
-
-    let backend = wdf_device_query_property_string(
-        device,
-        constants::DEVPROP_NUTHID_GUID,
-        constants::DEVPROP_NUTHID_KEY_BACKEND,
-    )?;
-
-    Ok(DeviceConfig {
-        host,
-        port,
-        backend,
+    nut_hid_properties::from_properties(&mut |property| {
+        wdf_device_query_property_data(device, constants::DEVPROP_NUTHID_GUID, property.key)
+            .ok()
+            .map(|(data, property_type)| (property_type, data))
     })
+    .map_err(|err| {
+        error!("Failed to read device properties: {}", err);
+        STATUS_DEVICE_CONFIGURATION_ERROR
+    })
+}
+
+fn device_error_status(err: &DeviceError) -> NTSTATUS {
+    match err {
+        DeviceError::InvalidBackend(_) => STATUS_NOT_SUPPORTED,
+        DeviceError::InvalidConfig(_) => STATUS_DEVICE_CONFIGURATION_ERROR,
+        DeviceError::Authentication(_) => STATUS_ACCESS_DENIED,
+        DeviceError::Descriptor(_) => STATUS_DEVICE_DATA_ERROR,
+        DeviceError::Backend { .. } | DeviceError::Nut(_) => STATUS_UNSUCCESSFUL,
+    }
 }
 
 extern "C" fn evt_driver_device_add(
@@ -196,9 +194,12 @@ extern "C" fn evt_driver_device_add(
     };
 
     debug!("Build hid descriptors");
-    let hid_device = Arc::new(
-        match nut_hid_device::DeviceEnum::from_config(device_config) {
-            Err(_error) => return STATUS_NOT_SUPPORTED,
+    let hid_device: Arc<dyn Device + Send + Sync> = Arc::from(
+        match nut_hid_device::from_config(device_config) {
+            Err(err) => {
+                error!("Failed to create device: {}", ErrorChain(&err));
+                return device_error_status(&err);
+            }
             Ok(device) => device,
         },
     );
@@ -340,6 +341,18 @@ fn get_report<'a>(memory: &'a WdfMemory) -> Result<(u8, &'a [u8]), NTSTATUS> {
     Ok((buffer[0], &buffer[1..]))
 }
 
+/* without report ids the buffer holds only the report, which has id 0 */
+fn get_written_report<'a>(
+    memory: &'a WdfMemory,
+    report_ids: bool,
+) -> Result<(u8, &'a [u8]), NTSTATUS> {
+    if report_ids {
+        get_report(memory)
+    } else {
+        Ok((0, memory.get_buffer()))
+    }
+}
+
 fn get_string_id(memory: &WdfMemory) -> Result<(u32, u32), NTSTATUS> {
     let buffer = memory.get_buffer();
 
@@ -399,9 +412,8 @@ fn get_indexed_string(request: &mut WdfRequest, data: &DeviceData) -> Result<(),
 
     debug!("get_indexed_string {string_id}");
 
-    let strings = &data.strings;
-    let data = strings
-        .get(&(string_id as u8))
+    let data = data
+        .indexed_string(string_id as u8)
         .ok_or(STATUS_INVALID_PARAMETER)?;
 
     request_copy_from_string(request, data)
@@ -411,15 +423,15 @@ fn get_indexed_string(request: &mut WdfRequest, data: &DeviceData) -> Result<(),
 fn read_report(request: &mut WdfRequest, device: &dyn Device) -> Result<(), NTSTATUS> {
     match device.read() {
         Some((report_id, report)) => {
-            debug!("read_report -> {report_id}");
-            copy_report_to_output(request, report_id, &report)?;
-
-            /* for now just update the reports */
-            let reports = &mut device.data().write().unwrap().reports;
-            reports.remove(&report_id);
-            reports.insert(report_id, report);
-
-            Ok(())
+            /* the backend has already updated the cached reports */
+            let data = device.data().read().unwrap();
+            debug!(
+                "read_report -> {}",
+                decode::describe(&data.report_descriptor, ReportType::Input, report_id, &report)
+            );
+            let report_ids = data.uses_report_ids();
+            drop(data);
+            copy_report_to_output(request, report_ids, report_id, &report)
         }
         None => {
             debug!("read_report -> None");
@@ -428,76 +440,84 @@ fn read_report(request: &mut WdfRequest, device: &dyn Device) -> Result<(), NTST
     }
 }
 
-fn write_report(_request: &mut WdfRequest, _device: &dyn Device) -> Result<(), NTSTATUS> {
-    debug!("write_report");
+// Output report written on the interrupt pipe, handled like SET_OUTPUT_REPORT
+fn write_report(request: &mut WdfRequest, device: &dyn Device) -> Result<(), NTSTATUS> {
+    let report_ids = device.data().read().unwrap().uses_report_ids();
+    let input_memory = request.get_input_memory()?;
+    let (report_id, report) = get_written_report(&input_memory, report_ids)?;
+
+    debug!("write_report {report_id}");
 
-    Err(STATUS_NOT_IMPLEMENTED)
+    device.set_output(report_id, report);
+    Ok(())
 }
 
 fn copy_report_to_output(
     request: &mut WdfRequest,
+    report_ids: bool,
     report_id: u8,
     report: &[u8],
 ) -> Result<(), NTSTATUS> {
     let mut offset = 0;
     let mut memory = request.get_output_memory()?;
-    offset += memory.copy_from_slice(slice::from_ref(&report_id), offset)?;
+    if report_ids {
+        offset += memory.copy_from_slice(slice::from_ref(&report_id), offset)?;
+    }
     offset += memory.copy_from_slice(report, offset)?;
     request.set_information(offset);
     Ok(())
 }
 
-fn get_report_internal(request: &mut WdfRequest, device_data: &DeviceData) -> Result<(), NTSTATUS> {
+fn get_report_internal(
+    request: &mut WdfRequest,
+    device_data: &DeviceData,
+    report_type: ReportType,
+) -> Result<(), NTSTATUS> {
     let input_memory = request.get_input_memory()?;
     let (report_id, _) = get_report(&input_memory)?;
 
-    debug!("get_report_internal {report_id}");
+    debug!("get_report_internal {report_type:?} {report_id}");
 
     let reports = &device_data.reports;
-    let data = reports.get(&report_id).ok_or(STATUS_INVALID_PARAMETER)?;
+    let data = reports
+        .get(report_type, report_id)
+        .ok_or(STATUS_INVALID_PARAMETER)?;
 
-    copy_report_to_output(request, report_id, data)?;
+    copy_report_to_output(request, device_data.uses_report_ids(), report_id, data)?;
 
     Ok(())
 }
 
 fn get_feature(request: &mut WdfRequest, device_data: &DeviceData) -> Result<(), NTSTATUS> {
     debug!("get_feature");
-    get_report_internal(request, device_data)
+    get_report_internal(request, device_data, ReportType::Feature)
 }
 
 fn get_input_report(request: &mut WdfRequest, device_data: &DeviceData) -> Result<(), NTSTATUS> {
-    debug!("get_feature");
-    get_report_internal(request, device_data)
+    debug!("get_input_report");
+    get_report_internal(request, device_data, ReportType::Input)
 }
 
-fn set_report_internal(
-    request: &mut WdfRequest,
-    device_data: &mut DeviceData,
-) -> Result<(), NTSTATUS> {
+fn set_output_report(request: &mut WdfRequest, device: &dyn Device) -> Result<(), NTSTATUS> {
+    let report_ids = device.data().read().unwrap().uses_report_ids();
     let input_memory = request.get_input_memory()?;
-    let (report_id, report) = get_report(&input_memory)?;
+    let (report_id, report) = get_written_report(&input_memory, report_ids)?;
 
-    debug!("set_report_internal {report_id}");
-
-    let reports = &mut device_data.reports;
-    reports.remove(&report_id);
-    reports.insert(report_id, report.to_vec());
+    debug!("set_output_report {report_id}");
 
+    device.set_output(report_id, report);
     Ok(())
 }
 
-fn set_output_report(
-    request: &mut WdfRequest,
-    device_data: &mut DeviceData,
-) -> Result<(), NTSTATUS> {
-    debug!("set_output_report");
-    set_report_internal(request, device_data)
-}
+fn set_feature(request: &mut WdfRequest, device: &dyn Device) -> Result<(), NTSTATUS> {
+    let report_ids = device.data().read().unwrap().uses_report_ids();
+    let input_memory = request.get_input_memory()?;
+    let (report_id, report) = get_written_report(&input_memory, report_ids)?;
+
+    debug!("set_feature {report_id}");
 
-fn set_feature(request: &mut WdfRequest, device_data: &mut DeviceData) -> Result<(), NTSTATUS> {
-    debug!("set_feature");
-    set_report_internal(request, device_data)
+    device.set_feature(report_id, report);
+    Ok(())
 }
 
 fn evt_io_device_control_internal(
@@ -548,13 +568,13 @@ fn evt_io_device_control_device(
             get_feature(request, &device.data().read().unwrap())?;
         }
         IOCTL_UMDF_HID_SET_FEATURE => {
-            set_feature(request, &mut device.data().write().unwrap())?;
+            set_feature(request, device)?;
         }
         IOCTL_UMDF_HID_GET_INPUT_REPORT => {
-            get_input_report(request, &mut device.data().read().unwrap())?;
+            get_input_report(request, &device.data().read().unwrap())?;
         }
         IOCTL_UMDF_HID_SET_OUTPUT_REPORT => {
-            set_output_report(request, &mut device.data().write().unwrap())?;
+            set_output_report(request, device)?;
         }
         _ => {
             warn!("Unsupported control");
@@ -10,12 +10,10 @@ use wdk_sys::{
     WDFOBJECT, WDFREQUEST, call_unsafe_wdf_function_binding,
 };
 use wdk_sys::{
-    DEVPROP_TYPE_STRING, DEVPROP_TYPE_UINT32, DEVPROPKEY, DEVPROPTYPE, GUID, STATUS_BAD_DATA,
-    STATUS_BUFFER_TOO_SMALL, STATUS_SUCCESS, WDF_DEVICE_PROPERTY_DATA,
+    DEVPROPKEY, DEVPROPTYPE, STATUS_BUFFER_TOO_SMALL, STATUS_SUCCESS, WDF_DEVICE_PROPERTY_DATA,
 };
 
 use std::marker::PhantomData;
-use crate::backports::from_utf16le_lossy;
 
 pub fn wdf_object_attributes_init() -> WDF_OBJECT_ATTRIBUTES {
     WDF_OBJECT_ATTRIBUTES {
@@ -253,7 +251,7 @@ pub fn wdf_device_query_property_ex(
     Ok((data, property_type))
 }
 
-fn wdf_device_query_property_data(
+pub fn wdf_device_query_property_data(
     device: *mut wdk_sys::WDFDEVICE__,
     fmtid: wdk_sys::GUID,
     pid: u32,
@@ -269,42 +267,3 @@ fn wdf_device_query_property_data(
     let (data, property_type) = wdf_device_query_property_ex(device, &mut device_property_data)?;
     Ok((data, property_type))
 }
-
-pub fn wdf_device_query_property_string(
-    device: WDFDEVICE,
-    fmtid: GUID,
-    pid: u32,
-) -> Result<String, NTSTATUS> {
-    let (data, property_type) = wdf_device_query_property_data(device, fmtid, pid)?;
-    if property_type != DEVPROP_TYPE_STRING {
-        warn!("Unexpected device property type: {property_type}");
-        return Err(STATUS_BAD_DATA);
-    }
-
-    let result = from_utf16le_lossy(&data);
-
-    match result.split_once(char::from(0)) {
-        Some((result, _)) => {
-            return Ok(result.to_string());
-        }
-        None => {
-            warn!("Unexpected device property data: {result}");
-            return Err(STATUS_BAD_DATA);
-        }
-    }
-}
-
-pub fn wdf_device_query_property_u32(
-    device: WDFDEVICE,
-    fmtid: GUID,
-    pid: u32,
-) -> Result<u32, NTSTATUS> {
-    let (data, property_type) = wdf_device_query_property_data(device, fmtid, pid)?;
-    if property_type != DEVPROP_TYPE_UINT32 {
-        warn!("Unexpected device property type: {property_type}");
-        return Err(STATUS_BAD_DATA);
-    }
-
-    let value: [u8; 4] = data.try_into().map_err(|_| STATUS_BAD_DATA)?;
-    Ok(u32::from_ne_bytes(value))
-}